@@ -1,9 +1,9 @@
 extern crate env_logger;
-#[macro_use]
 extern crate structopt;
 extern crate _rusted as rusted;
 
-use std::path::PathBuf;
+use std::env;
+use std::io::{self, IsTerminal};
 use structopt::StructOpt;
 use rusted::Config;
 
@@ -12,22 +12,44 @@ use rusted::Config;
 struct Opts {
     #[structopt(short = "p", long = "prompt")]
     pub prompt: Option<String>,
-    #[structopt(parse(from_os_str))]
-    pub file: Option<PathBuf>,
+    /// Don't print byte counts or `!` after shell commands
+    #[structopt(short = "s", long = "silent")]
+    pub silent: bool,
+    /// File to edit, or `!command` to edit its output
+    pub file: Option<String>,
+}
+
+/// Rewrite a bare `-`, the historical spelling of `-s`, leaving alone a `-`
+/// that is the prompt given to `-p`.
+fn rewrite_silent<I: IntoIterator<Item = String>>(args: I) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    for arg in args {
+        let is_prompt = match out.last() {
+            Some(prev) => prev == "-p" || prev == "--prompt",
+            None => false,
+        };
+        if arg == "-" && !is_prompt {
+            out.push("-s".into());
+        } else {
+            out.push(arg);
+        }
+    }
+    out
 }
 
 fn main() {
     env_logger::init().unwrap();
-    let opts = Opts::from_args();
-    let mut config = Config::default();
+    let opts = Opts::from_iter(rewrite_silent(env::args()));
+    let mut config = Config {
+        silent: opts.silent,
+        interactive: io::stdin().is_terminal(),
+        ..Default::default()
+    };
     if let Some(p) = opts.prompt {
         config.prompt = p.to_string();
         config.show_prompt = true;
     }
-    if let Some(f) = opts.file {
-        config.default_filename = Some(f);
-    }
-    if let Err(_) = rusted::run(&mut config) {
+    if rusted::run(&mut config, opts.file).is_err() {
         ::std::process::exit(1);
     }
 }
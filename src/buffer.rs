@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::Deref;
+
+use errors::*;
+use Config;
+
+/// The lines being edited.
+///
+/// Every line is given an id when it is added to the buffer, so that a line
+/// can be found again after lines around it have been inserted or deleted.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Buffer {
+    lines: Vec<String>,
+    ids: Vec<usize>,
+    /// the ids in `ids`, to tell quickly whether a line is still there
+    live: HashSet<usize>,
+    next_id: usize,
+    history: History,
+}
+
+/// A single reversible edit to the buffer
+#[derive(Debug, Clone, PartialEq)]
+enum Edit {
+    /// `Insert(idx, id, line)`
+    Insert(usize, usize, String),
+    /// `Remove(idx, id, line)`
+    Remove(usize, usize, String),
+    /// `Set(idx, old, new)`
+    Set(usize, String, String),
+}
+
+/// `.` and the marks, which are put back along with the lines on undo/redo
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Position {
+    current_index: Option<usize>,
+    marks: HashMap<char, usize>,
+}
+
+impl Position {
+    fn of(cfg: &Config) -> Position {
+        Position {
+            current_index: cfg.current_index,
+            marks: cfg.marks.clone(),
+        }
+    }
+
+    fn restore(&self, cfg: &mut Config) {
+        cfg.current_index = self.current_index;
+        cfg.marks = self.marks.clone();
+    }
+}
+
+/// All the edits made by one command
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    edits: Vec<Edit>,
+    before: Position,
+    after: Position,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct History {
+    /// the change being recorded, if a command is running
+    current: Option<Change>,
+    undo: Vec<Change>,
+    redo: Vec<Change>,
+    /// whether the last thing `u` did was to undo, so the next `u` redoes
+    undone: bool,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer::default()
+    }
+
+    /// Add an edit to the change being recorded. The edit is only built
+    /// when there is a change to add it to, to save copying lines for
+    /// commands that aren't recorded.
+    fn record<F: FnOnce() -> Edit>(&mut self, edit: F) {
+        if let Some(ref mut change) = self.history.current {
+            change.edits.push(edit());
+        }
+    }
+
+    pub fn insert(&mut self, idx: usize, line: String) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.insert_with_id(idx, id, line);
+    }
+
+    fn insert_with_id(&mut self, idx: usize, id: usize, line: String) {
+        self.record(|| Edit::Insert(idx, id, line.clone()));
+        self.lines.insert(idx, line);
+        self.ids.insert(idx, id);
+        self.live.insert(id);
+    }
+
+    /// Move the lines from `start` to `end` (inclusive) so that the first
+    /// of them ends up at `dest`, counted once they have been taken out. The
+    /// lines keep their ids, so marks on them still work.
+    pub fn move_lines(&mut self, start: usize, end: usize, dest: usize) {
+        let moved = (start..end + 1).map(|_| (self.ids[start], self.remove(start)))
+                                    .collect::<Vec<_>>();
+        for (n, (id, line)) in moved.into_iter().enumerate() {
+            self.insert_with_id(dest + n, id, line);
+        }
+    }
+
+    pub fn remove(&mut self, idx: usize) -> String {
+        let id = self.ids.remove(idx);
+        self.live.remove(&id);
+        let line = self.lines.remove(idx);
+        self.record(|| Edit::Remove(idx, id, line.clone()));
+        line
+    }
+
+    /// Replace the text of a line, keeping its id.
+    pub fn set(&mut self, idx: usize, line: String) {
+        if self.history.current.is_none() {
+            self.lines[idx] = line;
+            return;
+        }
+        let old = mem::replace(&mut self.lines[idx], line.clone());
+        self.record(|| Edit::Set(idx, old, line));
+    }
+
+    pub fn clear(&mut self) {
+        while let Some(last) = self.lines.len().checked_sub(1) {
+            self.remove(last);
+        }
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.lines.reserve(additional);
+        self.ids.reserve(additional);
+    }
+
+    /// The id of the line at `idx`
+    pub fn id(&self, idx: usize) -> usize {
+        self.ids[idx]
+    }
+
+    /// The current index of the line with the given id, if it is still in
+    /// the buffer. The search starts at `near` and works outwards, so it is
+    /// quick when the line hasn't moved far from there.
+    pub fn position(&self, id: usize, near: usize) -> Option<usize> {
+        if !self.live.contains(&id) {
+            return None;
+        }
+        let len = self.ids.len();
+        let near = near.min(len.saturating_sub(1));
+        for d in 0..len {
+            if near + d < len && self.ids[near + d] == id {
+                return Some(near + d);
+            }
+            if d < near && self.ids[near - d - 1] == id {
+                return Some(near - d - 1);
+            }
+        }
+        None
+    }
+
+    /// Start recording the edits made by a command.
+    pub fn begin_change(&mut self, cfg: &Config) {
+        self.history.current = Some(Change {
+            edits: vec![],
+            before: Position::of(cfg),
+            after: Position::default(),
+        });
+    }
+
+    /// Stop recording, keeping the change for undo if anything was edited.
+    /// Only the last `cfg.undo_levels` changes are kept.
+    pub fn end_change(&mut self, cfg: &Config) {
+        let mut change = match self.history.current.take() {
+            Some(change) => change,
+            None => return,
+        };
+        if change.edits.is_empty() {
+            return;
+        }
+        change.after = Position::of(cfg);
+        self.history.undo.push(change);
+        self.history.redo.clear();
+        self.history.undone = false;
+        let levels = cfg.undo_levels.max(1);
+        if self.history.undo.len() > levels {
+            let extra = self.history.undo.len() - levels;
+            self.history.undo.drain(..extra);
+        }
+    }
+
+    /// POSIX `u`: undo the last change, or redo it if the last `u` undid it.
+    pub fn undo(&mut self, cfg: &mut Config) -> Result<()> {
+        if self.history.undone {
+            self.redo_step(cfg)
+        } else {
+            self.undo_step(cfg)?;
+            self.history.undone = true;
+            Ok(())
+        }
+    }
+
+    /// Go back one change in the history.
+    pub fn undo_step(&mut self, cfg: &mut Config) -> Result<()> {
+        if self.history.current.is_some() {
+            return Err("Cannot undo while a change is being recorded".into());
+        }
+        let change = match self.history.undo.pop() {
+            Some(change) => change,
+            None => return Err("Nothing to undo".into()),
+        };
+        for edit in change.edits.iter().rev() {
+            match *edit {
+                Edit::Insert(idx, id, _) => {
+                    self.lines.remove(idx);
+                    self.ids.remove(idx);
+                    self.live.remove(&id);
+                },
+                Edit::Remove(idx, id, ref line) => {
+                    self.lines.insert(idx, line.clone());
+                    self.ids.insert(idx, id);
+                    self.live.insert(id);
+                },
+                Edit::Set(idx, ref old, _) => self.lines[idx] = old.clone(),
+            }
+        }
+        change.before.restore(cfg);
+        self.history.redo.push(change);
+        self.history.undone = false;
+        Ok(())
+    }
+
+    /// Go forward one change in the history, after going back.
+    pub fn redo_step(&mut self, cfg: &mut Config) -> Result<()> {
+        if self.history.current.is_some() {
+            return Err("Cannot redo while a change is being recorded".into());
+        }
+        let change = match self.history.redo.pop() {
+            Some(change) => change,
+            None => return Err("Nothing to redo".into()),
+        };
+        for edit in &change.edits {
+            match *edit {
+                Edit::Insert(idx, id, ref line) => {
+                    self.lines.insert(idx, line.clone());
+                    self.ids.insert(idx, id);
+                    self.live.insert(id);
+                },
+                Edit::Remove(idx, id, _) => {
+                    self.lines.remove(idx);
+                    self.ids.remove(idx);
+                    self.live.remove(&id);
+                },
+                Edit::Set(idx, _, ref new) => self.lines[idx] = new.clone(),
+            }
+        }
+        change.after.restore(cfg);
+        self.history.undo.push(change);
+        self.history.undone = false;
+        Ok(())
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+impl From<Vec<String>> for Buffer {
+    fn from(lines: Vec<String>) -> Buffer {
+        let mut buffer = Buffer::new();
+        buffer.reserve(lines.len());
+        for (idx, line) in lines.into_iter().enumerate() {
+            buffer.insert(idx, line);
+        }
+        buffer
+    }
+}
+
+impl PartialEq<Vec<String>> for Buffer {
+    fn eq(&self, other: &Vec<String>) -> bool {
+        &self.lines == other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Buffer;
+    use {lines, Config};
+
+    #[test]
+    fn ids_follow_lines_around_inserts_and_removes() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "c"]));
+        let id = buffer.id(2);
+        buffer.remove(0);
+        buffer.insert(0, "x".into());
+        buffer.insert(0, "y".into());
+        assert_eq!(buffer.position(id, 0), Some(3));
+        buffer.set(3, "z".into());
+        assert_eq!(buffer.position(id, 0), Some(3));
+        assert_eq!(buffer.position(id, 5), Some(3));
+        buffer.remove(3);
+        assert_eq!(buffer.position(id, 0), None);
+    }
+
+    #[test]
+    fn undo_twice_is_a_redo() {
+        let mut buffer = Buffer::from(lines(&["a", "b"]));
+        let mut config = Config {
+            current_index: Some(1),
+            ..Default::default()
+        };
+        assert!(buffer.undo(&mut config).is_err());
+
+        buffer.begin_change(&config);
+        buffer.remove(0);
+        config.current_index = Some(0);
+        buffer.end_change(&config);
+
+        assert!(buffer.undo(&mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "b"]));
+        assert_eq!(config.current_index, Some(1));
+        assert!(buffer.undo(&mut config).is_ok());
+        assert_eq!(buffer, lines(&["b"]));
+        assert_eq!(config.current_index, Some(0));
+    }
+
+    #[test]
+    fn unchanged_buffer_is_not_recorded() {
+        let mut buffer = Buffer::from(lines(&["a"]));
+        let mut config = Config::default();
+        buffer.begin_change(&config);
+        config.current_index = Some(0);
+        buffer.end_change(&config);
+        assert!(buffer.undo(&mut config).is_err());
+    }
+
+    #[test]
+    fn steps_back_and_forward_through_history() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "c"]));
+        let mut config = Config::default();
+        for idx in 0..3 {
+            buffer.begin_change(&config);
+            let upper = buffer[idx].to_uppercase();
+            buffer.set(idx, upper);
+            buffer.end_change(&config);
+        }
+        buffer.begin_change(&config);
+        buffer.clear();
+        buffer.end_change(&config);
+        assert!(buffer.is_empty());
+
+        assert!(buffer.undo_step(&mut config).is_ok());
+        assert_eq!(buffer, lines(&["A", "B", "C"]));
+        assert!(buffer.undo_step(&mut config).is_ok());
+        assert!(buffer.undo_step(&mut config).is_ok());
+        assert_eq!(buffer, lines(&["A", "b", "c"]));
+        assert!(buffer.redo_step(&mut config).is_ok());
+        assert_eq!(buffer, lines(&["A", "B", "c"]));
+
+        // a new change forgets what could have been redone
+        buffer.begin_change(&config);
+        buffer.insert(0, "new".into());
+        buffer.end_change(&config);
+        assert!(buffer.redo_step(&mut config).is_err());
+    }
+
+    #[test]
+    fn history_is_limited_to_undo_levels() {
+        let mut buffer = Buffer::new();
+        let mut config = Config {
+            undo_levels: 2,
+            ..Default::default()
+        };
+        for idx in 0..3 {
+            buffer.begin_change(&config);
+            buffer.insert(idx, idx.to_string());
+            buffer.end_change(&config);
+        }
+        assert!(buffer.undo_step(&mut config).is_ok());
+        assert!(buffer.undo_step(&mut config).is_ok());
+        assert!(buffer.undo_step(&mut config).is_err());
+        assert_eq!(buffer, lines(&["0"]));
+    }
+}
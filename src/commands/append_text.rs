@@ -1,47 +1,44 @@
 use parse::Addr;
-use commands::{insert_all, unknown};
+use commands::insert_all;
 use {Buffer, Config};
 use errors::*;
 
 pub fn cmd(text: &[String], line: Option<Addr>,
            buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
-    let (position, next_cur) = if buffer.is_empty() {
-        cfg.current_index = Some(0);
-        (0, if text.len() == 0 {
-            0
-         } else {
-            text.len() - 1
-        })
-    } else {
-        let position = line.unwrap_or(Addr::period())
-                            .resolve(buffer, cfg)?;
-        cfg.current_index = Some(position);
-        (position + 1, text.len())
+    // the text goes after line `position`, so `0` is before the first line.
+    // `1a` on an empty buffer is taken to mean `0a`.
+    let position = match line.unwrap_or(Addr::period()) {
+        ref addr if buffer.is_empty() && *addr == Addr::number(1) => 0,
+        addr => addr.line_number(buffer, cfg)?,
     };
-    let num_lines = text.len();
-    if num_lines == 0 {
-        return Err(unknown());
+    if position > 0 {
+        cfg.current_index = Some(position - 1);
     }
-    let _ = insert_all(buffer, position, &text);
-    cfg.update_curidx(next_cur);
+    // no text just moves `.` to the addressed line
+    if text.is_empty() {
+        return Ok(());
+    }
+    let _ = insert_all(buffer, position, text);
+    cfg.current_index = Some(position + text.len() - 1);
     cfg.dirty = true;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use {Config};
+    use {Buffer, Config};
     use parse::Addr;
     use super::cmd;
 
     #[test]
     fn empty_buffer_empty_input() {
         let text = vec![];
-        let mut buffer = vec![];
+        let mut buffer = Buffer::new();
         let mut config = Config::default();
         let res = cmd(&text, None, &mut buffer, &mut config);
-        assert!(res.is_err());
+        assert!(res.is_ok());
         assert_eq!(&buffer, &text);
+        assert_eq!(config.current_index, None);
     }
 
     #[test]
@@ -51,7 +48,7 @@ mod tests {
             "the quick brown fox".into(),
             "jumps over the lazy dog".into(),
         ];
-        let mut buffer = vec![];
+        let mut buffer = Buffer::new();
         let mut config = Config::default();
         let res = cmd(&text, None, &mut buffer, &mut config);
         assert!(res.is_ok());
@@ -65,7 +62,7 @@ mod tests {
             "the quick brown fox".into(),
             "jumps over the lazy dog".into(),
         ];
-        let mut buffer = vec![];
+        let mut buffer = Buffer::new();
         let mut config = Config::default();
         let addr = Some(Addr::number(0));
         let res = cmd(&text, addr, &mut buffer, &mut config);
@@ -81,7 +78,7 @@ mod tests {
             "the quick brown fox".into(),
             "jumps over the lazy dog".into(),
         ];
-        let mut buffer = vec![];
+        let mut buffer = Buffer::new();
         let mut config = Config::default();
         let addr = Some(Addr::number(1));
         let res = cmd(&text, addr, &mut buffer, &mut config);
@@ -90,6 +87,16 @@ mod tests {
         assert_eq!(config.current_index, Some(2));
     }
 
+    #[test]
+    fn line_past_the_end_is_an_error() {
+        let text = vec!["x".into()];
+        let mut buffer = Buffer::new();
+        let mut config = Config::default();
+        let res = cmd(&text, Some(Addr::number(5)), &mut buffer, &mut config);
+        assert!(res.is_err());
+        assert!(buffer.is_empty());
+    }
+
     #[test]
     fn explicit_line_populated_buffer() {
         let text = vec![
@@ -97,11 +104,13 @@ mod tests {
             "the quick brown fox".into(),
             "jumps over the lazy dog".into(),
         ];
-        let mut buffer = vec![
+        let mut buffer = Buffer::from(vec![
             "this is already here".into(),
-        ];
-        let mut config = Config::default();
-        config.current_index = Some(0);
+        ]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
         let addr = Some(Addr::number(1));
         let res = cmd(&text, addr, &mut buffer, &mut config);
         let expected: Vec<String> = vec![
@@ -118,16 +127,33 @@ mod tests {
     #[test]
     fn empty_input_text_changes_current_line_to_addressed_line() {
         let text = vec![];
-        let mut buffer = vec![
+        let mut buffer = Buffer::from(vec![
             "the quick brown fox".into(),
             "jumps over the lazy dog".into(),
             "lorem ipsum".into(),
-        ];
-        let mut config = Config::default();
-        config.current_index = Some(2);
+        ]);
+        let mut config = Config {
+            current_index: Some(2),
+            ..Default::default()
+        };
         let addr = Some(Addr::number(2));
         let _ = cmd(&text, addr, &mut buffer, &mut config);
         assert_eq!(&buffer, &buffer); // buffer is unchanged
         assert_eq!(config.current_index, Some(1));
     }
+
+    #[test]
+    fn line_zero_populated_buffer_goes_before_the_first_line() {
+        let text = vec!["first".into()];
+        let mut buffer = Buffer::from(vec!["second".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let res = cmd(&text, Some(Addr::number(0)), &mut buffer, &mut config);
+        assert!(res.is_ok());
+        let expected: Vec<String> = vec!["first".into(), "second".into()];
+        assert_eq!(&buffer, &expected);
+        assert_eq!(config.current_index, Some(0));
+    }
 }
@@ -1,4 +1,4 @@
-use commands::{unknown, insert_all};
+use commands::insert_all;
 use {Buffer, Config};
 use parse::LineRange;
 use errors::*;
@@ -6,55 +6,70 @@ use errors::*;
 pub fn cmd(text: &[String], range: Option<LineRange>,
         buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
     let num_lines = text.len();
-    if num_lines == 0 {
-        return Err(unknown());
-    }
     let range = range.unwrap_or(LineRange::current_line())
                         .resolve(buffer, cfg)?;
     let (start, end) = (range.0, range.1 + 1);
-    if !buffer.is_empty() {
-        for _ in start..end {
-            buffer.remove(start);
-        }
-    }
-    insert_all(buffer, start, &text)?;
-    cfg.update_curidx(num_lines - 1);
+    cfg.cut_buffer = (start..end).map(|_| buffer.remove(start))
+                                 .collect();
     cfg.dirty = true;
+    // with no text, `c` is the same as `d`
+    if num_lines == 0 {
+        cfg.current_index = if buffer.is_empty() {
+            None
+        } else {
+            Some(start.min(buffer.len() - 1))
+        };
+        return Ok(());
+    }
+    insert_all(buffer, start, text)?;
+    cfg.current_index = Some(start + num_lines - 1);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::cmd;
-    use {Config};
-    use parse::LineRange;
+    use {lines, Buffer, Config};
+    use parse::{Addr, LineRange, Mode};
 
     #[test]
-    fn default_line_empty_buffer_acts_like_append() {
+    fn default_line_empty_buffer_is_an_error() {
         let text = vec![
             "the quick brown fox".into(),
             "jumped over the lazy dog".into(),
         ];
-        let mut buffer = vec![];
+        let mut buffer = Buffer::new();
         let mut config = Config::default();
         let res = cmd(&text, None, &mut buffer, &mut config);
-        assert!(res.is_ok());
-        assert_eq!(&buffer, &text);
-        assert_eq!(config.current_index, Some(1));
+        assert!(res.is_err());
+        assert!(buffer.is_empty());
     }
 
     #[test]
-    fn explicit_line_one_empty_buffer() {
-        let text = vec![
-            "the quick brown fox".into(),
-            "jumped over the lazy dog".into(),
-        ];
-        let mut buffer = vec![];
+    fn line_past_the_end_is_an_error() {
+        let text = vec!["x".into()];
+        let mut buffer = Buffer::from(vec!["a".to_string()]);
         let mut config = Config::default();
-        let addr = Some(LineRange::current_line());
+        let addr = Some(LineRange::new(Some(Addr::number(9)), Mode::Comma, None));
         let res = cmd(&text, addr, &mut buffer, &mut config);
+        assert!(res.is_err());
+        assert_eq!(buffer, vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn no_text_deletes_the_lines() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "c"]));
+        let mut config = Config::default();
+        let addr = Some(LineRange::new(Some(Addr::number(2)), Mode::Comma, None));
+        let res = cmd(&[], addr, &mut buffer, &mut config);
         assert!(res.is_ok());
-        assert_eq!(&buffer, &text);
+        assert_eq!(buffer, lines(&["a", "c"]));
+        assert_eq!(config.cut_buffer, lines(&["b"]));
         assert_eq!(config.current_index, Some(1));
+
+        let res = cmd(&[], Some(LineRange::everything()), &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert!(buffer.is_empty());
+        assert_eq!(config.current_index, None);
     }
 }
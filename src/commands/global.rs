@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+use std::vec;
+
+use commands::{print_line, read_command, read_line, unknown, Command, PrintFlags};
+use {Buffer, Config};
+use parse::{self, LineRange};
+use pattern;
+use errors::*;
+
+/// Run `command_list` on every line in `range` matching `regex`, or on every
+/// line *not* matching it if `invert` is set (for `v`).
+pub fn cmd(range: Option<LineRange>, regex: &str, command_list: &str, invert: bool,
+           buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+    let mut marked = mark(range, regex, invert, buffer, cfg)?;
+
+    // second pass: run the command list with `.` set to each marked line
+    let commands = command_lines(command_list);
+    while let Some(idx) = marked.next(buffer) {
+        cfg.current_index = Some(idx);
+        cfg.command_list = Some(commands.clone());
+        let res = run_command_list(buffer, cfg);
+        cfg.command_list = None;
+        res?;
+    }
+    Ok(())
+}
+
+/// Print each line in `range` matching `regex` (or not matching it, for
+/// `V`) and run a command read from the user on it. An empty command skips
+/// the line and `&` repeats the previous command.
+pub fn interactive(range: Option<LineRange>, regex: &str, invert: bool,
+                   buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+    let mut marked = mark(range, regex, invert, buffer, cfg)?;
+    let mut previous: Option<String> = None;
+    while let Some(idx) = marked.next(buffer) {
+        cfg.current_index = Some(idx);
+        print_line(buffer, idx, PrintFlags::PRINT);
+        let line = match read_command(cfg) {
+            Some(line) => line,
+            None => break,
+        };
+        let line = match line.as_str() {
+            "" => continue,
+            "&" => match previous {
+                Some(ref p) => p.clone(),
+                None => return Err("No previous command".into()),
+            },
+            _ => line,
+        };
+        parse_non_global(&line)?.execute_in_global(buffer, cfg)?;
+        previous = Some(line);
+    }
+    Ok(())
+}
+
+/// The lines marked by the first pass of a global command, by id
+struct Marked {
+    ids: vec::IntoIter<usize>,
+    near: usize,
+}
+
+impl Marked {
+    /// The index of the next marked line, skipping any deleted by an earlier
+    /// command
+    fn next(&mut self, buffer: &Buffer) -> Option<usize> {
+        for id in &mut self.ids {
+            // the marked lines are in order, so each is usually found close
+            // to the previous one
+            if let Some(idx) = buffer.position(id, self.near) {
+                self.near = idx;
+                return Some(idx);
+            }
+        }
+        None
+    }
+}
+
+/// The first pass of the global commands: mark the lines to work on by id, so
+/// that they can still be found once lines are added and deleted.
+fn mark(range: Option<LineRange>, regex: &str, invert: bool,
+        buffer: &Buffer, cfg: &mut Config) -> Result<Marked> {
+    if buffer.is_empty() {
+        return Err(unknown());
+    }
+    let (start, end) = range.unwrap_or(LineRange::everything())
+                            .resolve(buffer, cfg)?;
+    let re = pattern::compile_or_last(regex, cfg)?;
+    let marked = (start..end + 1).filter(|&idx| re.is_match(&buffer[idx]) != invert)
+                                 .map(|idx| buffer.id(idx))
+                                 .collect::<Vec<_>>();
+    debug!("global: marked {} lines", marked.len());
+    Ok(Marked { ids: marked.into_iter(), near: 0 })
+}
+
+/// Split a command list into lines, dropping the backslash that continued
+/// each line onto the next. An empty command list means `p`.
+fn command_lines(command_list: &str) -> VecDeque<String> {
+    if command_list.is_empty() {
+        return vec!["p".to_string()].into();
+    }
+    let mut lines = command_list.split('\n').collect::<Vec<_>>();
+    let last = lines.pop();
+    lines.into_iter()
+         .map(|l| l.strip_suffix('\\').unwrap_or(l))
+         .chain(last)
+         .map(|l| l.to_owned())
+         .collect()
+}
+
+fn run_command_list(buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+    while let Some(line) = read_line(cfg) {
+        parse_non_global(&line)?.execute_in_global(buffer, cfg)?;
+    }
+    Ok(())
+}
+
+fn parse_non_global(line: &str) -> Result<Command> {
+    match parse::parse_command(line)? {
+        Command::Global(..) | Command::NotGlobal(..) |
+        Command::InteractiveGlobal(..) | Command::InteractiveNotGlobal(..) => {
+            Err("Cannot nest global commands".into())
+        },
+        // the global command is itself being recorded as one change
+        Command::Undo | Command::UndoSteps(..) | Command::RedoSteps(..) => {
+            Err("Cannot undo inside a global command".into())
+        },
+        command => Ok(command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cmd;
+    use {lines, Buffer, Config};
+
+    #[test]
+    fn deletes_every_matching_line() {
+        let mut buffer = Buffer::from(lines(&["a1", "b", "a2", "a3", "c"]));
+        let mut config = Config::default();
+        let res = cmd(None, "a", "d", false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["b", "c"]));
+    }
+
+    #[test]
+    fn skips_lines_deleted_during_the_pass() {
+        let mut buffer = Buffer::from(lines(&["a", "x", "b", "x"]));
+        let mut config = Config::default();
+        // the first marked line deletes everything after it, including the
+        // second marked line
+        let res = cmd(None, "x", ".,$d", false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["a"]));
+    }
+
+    #[test]
+    fn multi_line_command_list_with_input_text() {
+        let mut buffer = Buffer::from(lines(&["one", "two"]));
+        let mut config = Config::default();
+        let res = cmd(None, "o", "a\\\nnew\\\n.\\\ns/new/NEW/", false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["one", "NEW", "two", "NEW"]));
+        assert_eq!(config.current_index, Some(3));
+        assert!(config.command_list.is_none());
+    }
+
+    #[test]
+    fn omitted_period_ends_input_at_end_of_list() {
+        let mut buffer = Buffer::from(lines(&["one", "two"]));
+        let mut config = Config::default();
+        let res = cmd(None, "two", "i\\\nbefore", false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["one", "before", "two"]));
+    }
+
+    #[test]
+    fn inverted_runs_on_lines_that_do_not_match() {
+        let mut buffer = Buffer::from(lines(&["keep 1", "drop", "keep 2", "drop", "drop"]));
+        let mut config = Config::default();
+        let res = cmd(None, "keep", "d", true, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["keep 1", "keep 2"]));
+    }
+
+    #[test]
+    fn substitute_matching_nothing_on_a_marked_line_is_not_an_error() {
+        let mut buffer = Buffer::from(lines(&["foo bar", "foo", "bar"]));
+        let mut config = Config::default();
+        let res = cmd(None, "foo", "s/bar/X/", false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["foo X", "foo", "bar"]));
+    }
+
+    #[test]
+    fn interactive_commands_skip_and_repeat() {
+        use super::interactive;
+
+        let mut buffer = Buffer::from(lines(&["a1", "b", "a2", "a3"]));
+        // the commands would normally come from the user
+        let mut config = Config {
+            command_list: Some(lines(&["s/a/x/", "", "&"]).into()),
+            ..Default::default()
+        };
+        let res = interactive(None, "a", false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["x1", "b", "a2", "x3"]));
+
+        config.command_list = Some(lines(&["g/b/d"]).into());
+        assert!(interactive(None, "b", false, &mut buffer, &mut config).is_err());
+    }
+
+    #[test]
+    fn undo_is_rejected_in_the_command_list() {
+        use commands::Command;
+        use parse::{Addr, LineRange, Mode};
+
+        let mut buffer = Buffer::from(lines(&["a", "b", "c"]));
+        let mut config = Config::default();
+        let delete = Command::Delete(Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, None)));
+        assert!(delete.run(&mut buffer, &mut config).is_ok());
+
+        let global = Command::Global(None, "b".into(), "s/b/x/\\\nu".into());
+        assert!(global.run(&mut buffer, &mut config).is_err());
+        assert_eq!(buffer, lines(&["x", "c"]));
+
+        // the partial global is still undone as a single change
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["b", "c"]));
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["x", "c"]));
+    }
+}
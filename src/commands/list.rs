@@ -0,0 +1,80 @@
+use std::env;
+
+/// The width to fold `l` output to when `COLUMNS` isn't set
+const DEFAULT_WIDTH: usize = 72;
+
+/// The width of the terminal, from `COLUMNS`.
+pub fn terminal_width() -> usize {
+    env::var("COLUMNS").ok()
+                       .and_then(|c| c.parse().ok())
+                       .filter(|&w| w > 1)
+                       .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// Write `line` unambiguously, as POSIX `l` does: backslashes are doubled,
+/// non-printable characters are escaped, the end of the line is marked with
+/// `$`, and long lines are folded with a `\` so that no line of output is
+/// wider than `width`.
+pub fn format_line(line: &str, width: usize) -> String {
+    let mut out = String::with_capacity(line.len() + 2);
+    let mut column = 0;
+    let mut push = |text: &str, out: &mut String| {
+        let len = text.chars().count();
+        // an escape sequence is never split across a fold
+        if column + len > width - 1 {
+            out.push_str("\\\n");
+            column = 0;
+        }
+        out.push_str(text);
+        column += len;
+    };
+    for c in line.chars() {
+        match escape(c) {
+            Some(escaped) => push(&escaped, &mut out),
+            None => push(c.encode_utf8(&mut [0; 4]), &mut out),
+        }
+    }
+    out.push('$');
+    out
+}
+
+fn escape(c: char) -> Option<String> {
+    let escaped = match c {
+        '\\' => "\\\\",
+        '\x07' => "\\a",
+        '\x08' => "\\b",
+        '\x0c' => "\\f",
+        '\n' => "\\n",
+        '\r' => "\\r",
+        '\t' => "\\t",
+        '\x0b' => "\\v",
+        c if c.is_control() => {
+            let mut bytes = [0; 4];
+            return Some(c.encode_utf8(&mut bytes).bytes()
+                         .map(|b| format!("\\{:03o}", b))
+                         .collect());
+        },
+        _ => return None,
+    };
+    Some(escaped.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_line;
+
+    #[test]
+    fn escapes_unprintable_characters() {
+        assert_eq!(format_line("a\tb\r", 72), "a\\tb\\r$");
+        assert_eq!(format_line("back\\slash", 72), "back\\\\slash$");
+        assert_eq!(format_line("\x1b[0m\x7f", 72), "\\033[0m\\177$");
+        assert_eq!(format_line("caf\u{e9}", 72), "caf\u{e9}$");
+        assert_eq!(format_line("", 72), "$");
+    }
+
+    #[test]
+    fn folds_long_lines_without_splitting_escapes() {
+        assert_eq!(format_line("abcdefgh", 5), "abcd\\\nefgh$");
+        assert_eq!(format_line("abc\tdef", 5), "abc\\\n\\tde\\\nf$");
+    }
+}
@@ -1,4 +1,4 @@
-use parse::{LineRange, Addr};
+use parse::{LineRange, Addr, Mode};
 use {Buffer, Config, insert_all};
 use errors::*;
 
@@ -8,8 +8,13 @@ use std::io::{self, Write, BufRead, BufReader};
 
 mod append_text;
 mod change_text;
+mod global;
+mod list;
+mod read_file;
+mod scroll;
+mod shell;
+mod substitute;
 
-#[allow(dead_code)] // take this out when all the "TODO"s are gone
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     AppendText(Option<Addr>),
@@ -19,38 +24,107 @@ pub enum Command {
     UncondEditFile(Option<PathBuf>),
     SetDefaultFilename(String),
     GetDefaultFilename,
-    Global(Option<LineRange>, String, String),          // TODO
-    InteractiveGlobal(Option<LineRange>, String),       // TODO
+    Global(Option<LineRange>, String, String),
+    InteractiveGlobal(Option<LineRange>, String),
     LastError,
     ToggleErrorExpl,
     InsertText(Option<Addr>),
-    JoinLines(Option<LineRange>),                       // TODO
+    JoinLines(Option<LineRange>),
     MarkLine(Option<Addr>, char),
-    List(Option<LineRange>),                            // TODO
-    MoveLines(Option<LineRange>, Option<Addr>),     // TODO
+    List(Option<LineRange>),
+    MoveLines(Option<LineRange>, Option<Addr>),
     PrintNumbered(Option<LineRange>),
     Print(Option<LineRange>),
     ToggleShowPrompt,
     Quit,
     HardQuit,
-    ReadFile(Option<Addr>, String),                 // TODO
-    Substitute(Option<LineRange>, String, String),      // TODO
-    RepeatSubst(Option<LineRange>),                     // TODO
-    Transfer(Option<LineRange>, Option<Addr>),      // TODO
-    Undo,                                               // TODO
-    NotGlobal(Option<LineRange>, String, String),       // TODO
-    InteractiveNotGlobal(Option<LineRange>, String),    // TODO
+    ReadFile(Option<Addr>, Option<String>),
+    Substitute(Option<LineRange>, String, String, SubstFlags),
+    RepeatSubst(Option<LineRange>, RepeatFlags),
+    Transfer(Option<LineRange>, Option<Addr>),
+    Undo,
+    UndoSteps(usize),
+    RedoSteps(usize),
+    NotGlobal(Option<LineRange>, String, String),
+    InteractiveNotGlobal(Option<LineRange>, String),
     SaveFile(Option<LineRange>, Option<String>),
     SaveAndQuit(Option<LineRange>, Option<String>),
     SaveAppend(Option<LineRange>, Option<String>),
 
-    InsertFromCut(Option<Addr>),                    // TODO
-    YankToCut(Option<LineRange>),                       // TODO
-    Scroll(Option<Addr>),                           // TODO
-    ShellCmd(String),                                   // TODO
-    Comment(Option<LineRange>),                         // TODO
-    PrintLineNumber(Option<Addr>),                  // TODO
-    NullCmd(Option<Addr>),                          // TODO
+    InsertFromCut(Option<Addr>),
+    YankToCut(Option<LineRange>),
+    Scroll(Option<Addr>, Option<usize>),
+    ShellCmd(String),
+    Comment(Option<LineRange>),
+    PrintLineNumber(Option<Addr>),
+    NullCmd(Option<LineRange>),
+
+    /// A command followed by `p`, `l` or `n`, to print `.` once it has run
+    PrintSuffix(Box<Command>, PrintFlags),
+}
+
+/// Which of `p`, `l` and `n` were given after a command.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct PrintFlags {
+    pub print: bool,
+    pub list: bool,
+    pub numbered: bool,
+}
+
+impl PrintFlags {
+    pub const PRINT: PrintFlags = PrintFlags { print: true, list: false, numbered: false };
+    pub const LIST: PrintFlags = PrintFlags { print: false, list: true, numbered: false };
+    pub const NUMBERED: PrintFlags = PrintFlags { print: false, list: false, numbered: true };
+
+    pub fn any(&self) -> bool {
+        self.print || self.list || self.numbered
+    }
+
+    /// The flags set in either `self` or `other`
+    pub fn union(self, other: PrintFlags) -> PrintFlags {
+        PrintFlags {
+            print: self.print || other.print,
+            list: self.list || other.list,
+            numbered: self.numbered || other.numbered,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubstFlags {
+    /// replace every match from the `nth` onwards, not just the `nth`
+    pub global: bool,
+    pub nth: usize,
+}
+
+/// The flags of `s` with no regex or replacement, which repeats the last
+/// substitution
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RepeatFlags {
+    /// `g`: replace every match if the last substitution didn't, and vice versa
+    pub toggle_global: bool,
+    /// a count, to replace a different match than last time
+    pub nth: Option<usize>,
+    /// `r`: use the last regex used anywhere, not just by `s`
+    pub last_regex: bool,
+}
+
+/// What the last `s` did, for `&`, `s` with no arguments, and `%` as a
+/// replacement
+#[derive(Debug, PartialEq, Clone)]
+pub struct Substitution {
+    pub regex: String,
+    pub replacement: String,
+    pub flags: SubstFlags,
+}
+
+impl Default for SubstFlags {
+    fn default() -> SubstFlags {
+        SubstFlags {
+            global: false,
+            nth: 1,
+        }
+    }
 }
 
 fn unknown() -> Error {
@@ -61,16 +135,46 @@ fn exit() -> Error {
     ErrorKind::Exit.into()
 }
 
-pub fn input_mode() -> Vec<String> {
+/// Read a line of input, from the command list being run by `g` if there is
+/// one, or from stdin otherwise. Returns `None` at the end of the input.
+pub fn read_line(cfg: &mut Config) -> Option<String> {
+    if let Some(ref mut commands) = cfg.command_list {
+        return commands.pop_front();
+    }
+    let mut s = String::new();
+    match io::stdin().read_line(&mut s) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(s.trim_end_matches(&['\n', '\r'][..]).into()),
+    }
+}
+
+fn ends_with_escape(line: &str) -> bool {
+    line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1
+}
+
+/// Read a command, joining lines that end in an unescaped backslash (such as
+/// a replacement containing an escaped newline) into one.
+pub fn read_command(cfg: &mut Config) -> Option<String> {
+    let mut command = read_line(cfg)?;
+    while ends_with_escape(&command) {
+        match read_line(cfg) {
+            Some(line) => {
+                command.push('\n');
+                command.push_str(&line);
+            },
+            None => break,
+        }
+    }
+    Some(command)
+}
+
+pub fn input_mode(cfg: &mut Config) -> Vec<String> {
     let mut inp = vec![];
-    loop {
-        let mut s = String::new();
-        let _ = io::stdin().read_line(&mut s);
-        let s = s.trim();
-        if &s[..] == "." {
+    while let Some(s) = read_line(cfg) {
+        if s == "." {
             break;
         }
-        inp.push(s.into());
+        inp.push(s);
     }
     inp
 }
@@ -97,15 +201,19 @@ fn get_filename(filename: Option<String>, cfg: &mut Config) -> Option<PathBuf> {
     }
 }
 
-fn save_file(start: usize, end: usize,
+/// Write the lines from `start` up to (but not including) `end` to
+/// `filename`, or to a shell command for `w !command`, returning the number
+/// of bytes written.
+pub fn save_file(start: usize, end: usize,
                 open_options: &mut OpenOptions,
                 filename: Option<String>, buffer: &mut Buffer,
-                cfg: &mut Config) -> Result<()>
+                cfg: &mut Config) -> Result<usize>
 {
     if let Some(ref f) = filename {
-        // system command
-        if f.trim().starts_with("!") {
-            return Err(unknown());
+        // `w !command` writes to the command, not a file, so the buffer is
+        // still unsaved
+        if let Some(command) = f.trim().strip_prefix('!') {
+            return shell::write_to_command(&buffer[start..end], command, cfg);
         }
     }
 
@@ -117,23 +225,65 @@ fn save_file(start: usize, end: usize,
     let path = Path::new(&filename);
     if !path.exists() {
         debug!("file does not exist");
-        if confirm("file does not exist. create it?") {
+        // a script can't answer, so it always gets a new file
+        if !cfg.interactive || confirm("file does not exist. create it?") {
             open_options.create(true);
         } else {
             return Err("file does not exist".into());
         }
     }
-    let mut fp = match open_options.open(&path) {
+    let mut fp = match open_options.open(path) {
         Ok(f) => f,
         Err(_) => {
             return Err("Could not open file".into());
         }
     };
-    for idx in start..end {
-        let _ = writeln!(fp, "{}", buffer[idx]);
+    let mut bytes = 0;
+    for line in &buffer[start..end] {
+        if writeln!(fp, "{}", line).is_err() {
+            return Err("Could not write file".into());
+        }
+        bytes += line.len() + 1;
     }
     cfg.dirty = false;
-    Ok(())
+    Ok(bytes)
+}
+
+/// Print the number of bytes read or written, unless in silent mode.
+pub(crate) fn print_count(bytes: usize, cfg: &Config) {
+    if !cfg.silent {
+        let _ = writeln!(&mut io::stdout(), "{}", bytes);
+        let _ = io::stdout().flush();
+    }
+}
+
+fn print_line(buffer: &Buffer, idx: usize, flags: PrintFlags) {
+    if !flags.any() {
+        return;
+    }
+    let line = if flags.list {
+        list::format_line(&buffer[idx], list::terminal_width())
+    } else {
+        buffer[idx].clone()
+    };
+    if flags.numbered {
+        let _ = writeln!(&mut io::stdout(), "{}\t{}", idx + 1, line);
+    } else {
+        let _ = writeln!(&mut io::stdout(), "{}", line);
+    }
+    let _ = io::stdout().flush();
+}
+
+/// The lines `w` and friends should write, as a half-open range. An empty
+/// buffer can be written, as long as no lines were asked for.
+fn lines_to_write(range: Option<LineRange>, buffer: &Buffer,
+                  cfg: &mut Config) -> Result<(usize, usize)> {
+    if buffer.is_empty() && range.is_none() {
+        return Ok((0, 0));
+    }
+    let range = range.unwrap_or(LineRange::everything())
+                     .resolve(buffer, cfg)?;
+    Ok((range.0, range.1 + 1))
 }
 
 fn confirm(msg: &str) -> bool {
@@ -141,49 +291,108 @@ fn confirm(msg: &str) -> bool {
     let _ = io::stdout().flush();
     let mut inp = String::new();
     let _ = io::stdin().read_line(&mut inp);
-    &inp.trim()[..] == "y"
+    inp.trim() == "y"
+}
+
+/// Whether it's alright to throw away the buffer: it has no unsaved changes,
+/// or the user says so. A script is never asked; it just gets an error.
+fn can_discard(msg: &str, cfg: &Config) -> Result<bool> {
+    if !cfg.dirty {
+        return Ok(true);
+    }
+    if !cfg.interactive {
+        return Err("Warning: buffer modified".into());
+    }
+    Ok(confirm(msg))
 }
 
 fn quit(cfg: &mut Config) -> Result<()> {
-    if cfg.dirty {
-        if confirm("unsaved changes. really exit?") {
-            Err(exit())
-        } else {
-            Ok(())
-        }
-    } else {
+    if can_discard("unsaved changes. really exit?", cfg)? {
         Err(exit())
+    } else {
+        Ok(())
+    }
+}
+
+/// Read lines until the end of `reader`, returning them without their
+/// newlines, along with the number of bytes read.
+fn read_lines<R: BufRead>(mut reader: R) -> Result<(Vec<String>, usize)> {
+    let mut lines = vec![];
+    let mut bytes = 0;
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(n) => bytes += n,
+            Err(_) => return Err("error reading from file".into()),
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        lines.push(line);
     }
+    Ok((lines, bytes))
 }
 
-fn edit_file<P: AsRef<Path>>(filename: P, buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+fn read_path<P: AsRef<Path>>(filename: P) -> Result<(Vec<String>, usize)> {
     let path = filename.as_ref();
     if !path.exists() {
-        return Err(unknown());
+        return Err("No such file or directory".into());
     }
-    let fil = match File::open(&path) {
+    let fil = match File::open(path) {
         Ok(f) => f,
         Err(_) => {
             return Err("error opening file".into());
         }
     };
-    let reader = BufReader::new(fil);
-    let lines = reader.lines();
-    let size_hint = match lines.size_hint() {
-        (_, Some(upper)) => upper,
-        (lower, _) => lower,
+    read_lines(BufReader::new(fil))
+}
+
+/// Replace the buffer with the contents of `filename`, returning the number of
+/// bytes read.
+pub fn edit_file<P: AsRef<Path>>(filename: P, buffer: &mut Buffer, cfg: &mut Config) -> Result<usize> {
+    let (next_buffer, bytes) = read_path(filename)?;
+    replace_buffer(next_buffer, buffer, cfg);
+    Ok(bytes)
+}
+
+/// Load the file named on the command line, or the output of a shell command
+/// if it starts with `!`, returning the number of bytes read. The file becomes
+/// the default filename, even if it doesn't exist yet.
+pub fn load_file(file: &str, buffer: &mut Buffer, cfg: &mut Config) -> Result<usize> {
+    let (lines, bytes) = match file.strip_prefix('!') {
+        Some(command) => shell::read_from_command(command, cfg)?,
+        None => {
+            cfg.default_filename = Some(file.into());
+            read_path(file)?
+        },
     };
-    let mut next_buffer = Vec::with_capacity(size_hint);
-    for (idx, line) in lines.enumerate() {
-        let line = match line {
-            Ok(l) => l,
-            Err(_) => {
-                return Err("error reading from file".into());
-            },
-        };
-        next_buffer.insert(idx, line);
+    replace_buffer(lines, buffer, cfg);
+    Ok(bytes)
+}
+
+/// Insert the contents of `filename` (or the output of a shell command if it
+/// starts with `!`) after line number `line`, where `0` is before the first
+/// line, returning the number of bytes read.
+pub fn read_file(line: usize, filename: Option<String>,
+                 buffer: &mut Buffer, cfg: &mut Config) -> Result<usize> {
+    let command = filename.as_ref().and_then(|f| f.strip_prefix('!'));
+    let (lines, bytes) = match command {
+        Some(command) => shell::read_from_command(command, cfg)?,
+        None => match get_filename(filename, cfg) {
+            Some(f) => read_path(f)?,
+            None => return Err("No filename".into()),
+        },
+    };
+    insert_all(buffer, line, &lines)?;
+    if !lines.is_empty() {
+        cfg.current_index = Some(line + lines.len() - 1);
+        cfg.dirty = true;
     }
+    Ok(bytes)
+}
 
+fn replace_buffer(next_buffer: Vec<String>, buffer: &mut Buffer, cfg: &mut Config) {
     if next_buffer.len() > buffer.len() {
         // reserve some more capacity for the buffer
         let extra = next_buffer.len() - buffer.len();
@@ -196,30 +405,100 @@ fn edit_file<P: AsRef<Path>>(filename: P, buffer: &mut Buffer, cfg: &mut Config)
         buffer.insert(idx, elem);
     }
 
-    cfg.current_index = Some(buffer.len() - 1);
+    cfg.current_index = buffer.len().checked_sub(1);
+    cfg.dirty = false;
+}
+
+/// Print the lines in `range` (`.` by default), leaving `.` at the last one.
+fn print_range(range: Option<LineRange>, flags: PrintFlags,
+               buffer: &Buffer, cfg: &mut Config) -> Result<()> {
+    if buffer.is_empty() {
+        return Err(unknown());
+    }
 
+    let (start, end) = range.unwrap_or(LineRange::current_line())
+                            .resolve(buffer, cfg)?;
+    for idx in start..end + 1 {
+        print_line(buffer, idx, flags);
+    }
+    cfg.current_index = Some(end);
     Ok(())
 }
 
 impl Command {
+    /// Add a print suffix to the command, merging it with any it already has.
+    pub fn with_suffix(self, flags: PrintFlags) -> Command {
+        if !flags.any() {
+            return self;
+        }
+        match self {
+            Command::PrintSuffix(command, f) => Command::PrintSuffix(command, f.union(flags)),
+            command => Command::PrintSuffix(Box::new(command), flags),
+        }
+    }
+
+    /// Whether the command can change the buffer, and so can be undone
+    fn modifies_buffer(&self) -> bool {
+        if let Command::PrintSuffix(ref command, _) = *self {
+            return command.modifies_buffer();
+        }
+        matches!(*self,
+            Command::AppendText(..) | Command::ChangeText(..) | Command::Delete(..) |
+            Command::EditFile(..) | Command::UncondEditFile(..) | Command::Global(..) | Command::InteractiveGlobal(..) | Command::InsertText(..) |
+            Command::JoinLines(..) | Command::MoveLines(..) | Command::ReadFile(..) |
+            Command::Substitute(..) | Command::RepeatSubst(..) | Command::Transfer(..) |
+            Command::NotGlobal(..) | Command::InteractiveNotGlobal(..) |
+            Command::InsertFromCut(..))
+    }
+
+    /// Run the command, recording the changes it makes to the buffer so that
+    /// they can be undone.
     pub fn run(self, buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+        if !self.modifies_buffer() {
+            return self.execute(buffer, cfg);
+        }
+        buffer.begin_change(cfg);
+        let res = self.execute(buffer, cfg);
+        // even a failed command may have changed something (e.g. `g` when a
+        // command in its list fails part way through)
+        buffer.end_change(cfg);
+        res
+    }
+
+    /// Run the command without recording it for `u`, for commands that are
+    /// part of a larger change (such as the command list of `g`).
+    pub fn execute(self, buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+        self.execute_in(buffer, cfg, false)
+    }
+
+    /// Run the command from the command list of a global command, where `s`
+    /// matching nothing on a marked line is not an error.
+    pub fn execute_in_global(self, buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+        self.execute_in(buffer, cfg, true)
+    }
+
+    fn execute_in(self, buffer: &mut Buffer, cfg: &mut Config, in_global: bool) -> Result<()> {
         match self {
             Command::AppendText(line) => {
-                let text = input_mode();
+                let text = input_mode(cfg);
                 append_text::cmd(&text, line, buffer, cfg)
             },
             Command::ChangeText(range) => {
-                let text = input_mode();
+                let text = input_mode(cfg);
                 change_text::cmd(&text, range, buffer, cfg)
             },
             Command::Delete(range) => {
                 let range = range.unwrap_or(LineRange::current_line())
                                  .resolve(buffer, cfg)?;
                 let (start, end) = (range.0, range.1 + 1);
-                for _ in start..end {
-                    buffer.remove(start);
-                }
-                cfg.current_index = Some(start);
+                cfg.cut_buffer = (start..end).map(|_| buffer.remove(start))
+                                             .collect();
+                // `.` is the line after the deleted ones, or the new last line
+                cfg.current_index = if buffer.is_empty() {
+                    None
+                } else {
+                    Some(start.min(buffer.len() - 1))
+                };
                 cfg.dirty = true;
                 Ok(())
             },
@@ -236,10 +515,14 @@ impl Command {
                         }
                     }
                 };
-                if cfg.dirty && !confirm("unsaved changes. really edit?") {
+                if !can_discard("unsaved changes. really edit?", cfg)? {
                     return Ok(());
                 }
-                edit_file(&filename, buffer, cfg)
+                // the file edited is the one `w` writes to from now on
+                cfg.default_filename = Some(filename.clone());
+                let bytes = edit_file(&filename, buffer, cfg)?;
+                print_count(bytes, cfg);
+                Ok(())
             },
             Command::UncondEditFile(filename) => {
                 let filename = match filename {
@@ -254,7 +537,10 @@ impl Command {
                         }
                     }
                 };
-                edit_file(&filename, buffer, cfg)
+                cfg.default_filename = Some(filename.clone());
+                let bytes = edit_file(&filename, buffer, cfg)?;
+                print_count(bytes, cfg);
+                Ok(())
             },
             Command::SetDefaultFilename(filename) => {
                 cfg.default_filename = Some(filename.trim().into());
@@ -264,9 +550,9 @@ impl Command {
                 match cfg.default_filename {
                     Some(ref f) => {
                         debug!("default filename: {:?}", f);
-                        return Ok(());
+                        Ok(())
                     },
-                    None => return Err(unknown()),
+                    None => Err(unknown()),
                 }
             },
             Command::LastError => {
@@ -280,51 +566,150 @@ impl Command {
                 Ok(())
             },
             Command::InsertText(line) => {
-                let text = input_mode();
-                let line = if buffer.is_empty() {
-                    0
-                } else {
-                    line.unwrap_or(Addr::period()).resolve(buffer, cfg)?
-                };
-                let _ = insert_all(buffer, line, &text);
-                cfg.update_curidx(text.len() - 1);
+                let text = input_mode(cfg);
+                // inserting before line `0` is the same as before line `1`
+                let position = line.unwrap_or(Addr::period())
+                                   .line_number(buffer, cfg)?
+                                   .saturating_sub(1);
+                // no text just moves `.` to the addressed line
+                if text.is_empty() {
+                    if !buffer.is_empty() {
+                        cfg.current_index = Some(position);
+                    }
+                    return Ok(());
+                }
+                let _ = insert_all(buffer, position, &text);
+                cfg.current_index = Some(position + text.len() - 1);
                 cfg.dirty = true;
                 Ok(())
             },
+            Command::JoinLines(range) => {
+                let (start, end) = range.unwrap_or(LineRange::current_and_next())
+                                        .resolve(buffer, cfg)?;
+                if start == end {
+                    return Ok(());
+                }
+                cfg.cut_buffer = buffer[start..end + 1].to_vec();
+                for _ in start..end {
+                    buffer.remove(start + 1);
+                }
+                buffer.set(start, cfg.cut_buffer.concat());
+                cfg.current_index = Some(start);
+                cfg.dirty = true;
+                Ok(())
+            },
+            Command::MoveLines(range, dest) => {
+                let (start, end) = range.unwrap_or(LineRange::current_line())
+                                        .resolve(buffer, cfg)?;
+                // the lines go after line `dest`, which can't be one of them
+                let dest = dest.unwrap_or(Addr::period()).line_number(buffer, cfg)?;
+                if dest > start && dest <= end {
+                    return Err("Invalid destination".into());
+                }
+                let count = end - start + 1;
+                let dest = if dest > end { dest - count } else { dest };
+                buffer.move_lines(start, end, dest);
+                cfg.current_index = Some(dest + count - 1);
+                cfg.dirty = true;
+                Ok(())
+            },
+            Command::Transfer(range, dest) => {
+                let (start, end) = range.unwrap_or(LineRange::current_line())
+                                        .resolve(buffer, cfg)?;
+                let dest = dest.unwrap_or(Addr::period()).line_number(buffer, cfg)?;
+                let lines = buffer[start..end + 1].to_vec();
+                insert_all(buffer, dest, &lines)?;
+                cfg.current_index = Some(dest + lines.len() - 1);
+                cfg.dirty = true;
+                Ok(())
+            },
+            Command::ReadFile(line, filename) => {
+                let bytes = read_file::cmd(line, filename, buffer, cfg)?;
+                print_count(bytes, cfg);
+                Ok(())
+            },
             Command::MarkLine(line, mark) => {
                 let line = line.unwrap_or(Addr::period())
                                .resolve(buffer, cfg)?;
                 debug!("Putting mark {} at line {}", mark, line);
-                cfg.marks.insert(mark, line);
+                // by id, so the mark follows the line as others come and go
+                cfg.marks.insert(mark, buffer.id(line));
                 Ok(())
             },
             Command::Print(range) => {
-                if buffer.len() == 0 {
-                    return Err(unknown());
+                print_range(range, PrintFlags::PRINT, buffer, cfg)
+            },
+            Command::List(range) => {
+                print_range(range, PrintFlags::LIST, buffer, cfg)
+            },
+            Command::PrintNumbered(range) => {
+                print_range(range, PrintFlags::NUMBERED, buffer, cfg)
+            },
+            Command::YankToCut(range) => {
+                let (start, end) = range.unwrap_or(LineRange::current_line())
+                                        .resolve(buffer, cfg)?;
+                cfg.cut_buffer = buffer[start..end + 1].to_vec();
+                Ok(())
+            },
+            Command::InsertFromCut(line) => {
+                if cfg.cut_buffer.is_empty() {
+                    return Err("Nothing to put".into());
                 }
-
-                let range = range.unwrap_or(LineRange::current_line())
-                                 .resolve(buffer, cfg)?;
-                let (start, end) = (range.0, range.1 + 1);
-                let _ = writeln!(&mut io::stdout(), "{}",
-                                buffer[start..end].join("\n"));
+                let position = line.unwrap_or(Addr::period())
+                                   .line_number(buffer, cfg)?;
+                let lines = cfg.cut_buffer.clone();
+                insert_all(buffer, position, &lines)?;
+                cfg.current_index = Some(position + lines.len() - 1);
+                cfg.dirty = true;
+                Ok(())
+            },
+            Command::Scroll(line, count) => {
+                scroll::cmd(line, count, PrintFlags::PRINT, buffer, cfg)
+            },
+            Command::ShellCmd(command) => shell::cmd(&command, cfg),
+            Command::PrintLineNumber(line) => {
+                // `0` for an empty buffer
+                let line = line.unwrap_or(Addr::dollar_sign())
+                               .line_number(buffer, cfg)?;
+                let _ = writeln!(&mut io::stdout(), "{}", line);
                 let _ = io::stdout().flush();
                 Ok(())
             },
-            Command::PrintNumbered(range) => {
-                if buffer.len() == 0 {
-                    return Err(unknown());
+            Command::Comment(range) => {
+                // the rest of the line is ignored, but an address still sets `.`
+                if let Some(range) = range {
+                    let (_, end) = range.resolve(buffer, cfg)?;
+                    cfg.current_index = Some(end);
+                }
+                Ok(())
+            },
+            Command::NullCmd(range) => {
+                // print the last line addressed, or the next line
+                let range = range.unwrap_or_else(|| {
+                    LineRange::new(Some(Addr::next_line()), Mode::Comma, None)
+                });
+                let (_, end) = range.resolve(buffer, cfg)?;
+                print_line(buffer, end, PrintFlags::PRINT);
+                cfg.current_index = Some(end);
+                Ok(())
+            },
+            Command::Undo => {
+                buffer.undo(cfg)?;
+                cfg.dirty = true;
+                Ok(())
+            },
+            Command::UndoSteps(count) => {
+                for _ in 0..count {
+                    buffer.undo_step(cfg)?;
+                    cfg.dirty = true;
+                }
+                Ok(())
+            },
+            Command::RedoSteps(count) => {
+                for _ in 0..count {
+                    buffer.redo_step(cfg)?;
+                    cfg.dirty = true;
                 }
-
-                let range = range.unwrap_or(LineRange::current_line())
-                                 .resolve(buffer, cfg)?;
-                let (start, end) = (range.0, range.1 + 1);
-                let buf = buffer.iter().enumerate().map(|(ref idx, ref line)| {
-                    format!("{}\t{}", idx + 1, line)
-                }).collect::<Vec<_>>();
-                let _ = writeln!(&mut io::stdout(), "{}",
-                                buf[start..end].join("\n"));
-                let _ = io::stdout().flush();
                 Ok(())
             },
             Command::ToggleShowPrompt => {
@@ -335,32 +720,438 @@ impl Command {
             Command::Quit => {
                 quit(cfg)
             },
+            Command::Global(range, regex, commands) => {
+                global::cmd(range, &regex, &commands, false, buffer, cfg)
+            },
+            Command::NotGlobal(range, regex, commands) => {
+                global::cmd(range, &regex, &commands, true, buffer, cfg)
+            },
+            Command::InteractiveGlobal(range, regex) => {
+                global::interactive(range, &regex, false, buffer, cfg)
+            },
+            Command::InteractiveNotGlobal(range, regex) => {
+                global::interactive(range, &regex, true, buffer, cfg)
+            },
+            Command::Substitute(range, regex, replacement, flags) => {
+                substitute::cmd(range, &regex, &replacement, flags, in_global, buffer, cfg)
+            },
+            Command::RepeatSubst(range, flags) => {
+                substitute::repeat(range, flags, in_global, buffer, cfg)
+            },
             Command::SaveFile(range, filename) => {
-                let range = range.unwrap_or(LineRange::everything())
-                                 .resolve(buffer, cfg)?;
-                let start = range.0;
-                let end = range.1 + 1;
+                let (start, end) = lines_to_write(range, buffer, cfg)?;
                 let mut oo = OpenOptions::new();
-                save_file(start, end, oo.truncate(true).write(true), filename, buffer, cfg)
+                let bytes = save_file(start, end, oo.truncate(true).write(true), filename, buffer, cfg)?;
+                print_count(bytes, cfg);
+                Ok(())
             },
             Command::SaveAndQuit(range, filename) => {
-                let range = range.unwrap_or(LineRange::everything())
-                                 .resolve(buffer, cfg)?;
-                let start = range.0;
-                let end = range.1 + 1;
+                let (start, end) = lines_to_write(range, buffer, cfg)?;
                 let mut oo = OpenOptions::new();
-                save_file(start, end, oo.write(true), filename, buffer, cfg)?;
+                let bytes = save_file(start, end, oo.truncate(true).write(true), filename, buffer, cfg)?;
+                print_count(bytes, cfg);
                 quit(cfg)
             },
             Command::SaveAppend(range, filename) => {
-                let range = range.unwrap_or(LineRange::everything())
-                                 .resolve(buffer, cfg)?;
-                let start = range.0;
-                let end = range.1 + 1;
+                let (start, end) = lines_to_write(range, buffer, cfg)?;
                 let mut oo = OpenOptions::new();
-                save_file(start, end, oo.append(true), filename, buffer, cfg)
+                let bytes = save_file(start, end, oo.append(true), filename, buffer, cfg)?;
+                print_count(bytes, cfg);
+                Ok(())
             },
-            _ => Ok(()),
+            Command::PrintSuffix(command, flags) => {
+                // `pn` and the like print the lines just once
+                match *command {
+                    Command::Print(range) => {
+                        return print_range(range, flags.union(PrintFlags::PRINT), buffer, cfg);
+                    },
+                    Command::List(range) => {
+                        return print_range(range, flags.union(PrintFlags::LIST), buffer, cfg);
+                    },
+                    Command::PrintNumbered(range) => {
+                        return print_range(range, flags.union(PrintFlags::NUMBERED), buffer, cfg);
+                    },
+                    Command::Scroll(line, count) => {
+                        return scroll::cmd(line, count, flags.union(PrintFlags::PRINT), buffer, cfg);
+                    },
+                    command => command.execute_in(buffer, cfg, in_global)?,
+                }
+                match cfg.current_index {
+                    Some(idx) if idx < buffer.len() => {
+                        print_line(buffer, idx, flags);
+                        Ok(())
+                    },
+                    _ => Err(unknown()),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, PrintFlags, SubstFlags};
+    use errors::{Error, ErrorKind};
+    use parse::{Addr, LineRange, Mode};
+    use {lines, Buffer, Config};
+
+    #[test]
+    fn read_lines_keeps_carriage_returns() {
+        use super::read_lines;
+
+        let (read, bytes) = read_lines(&b"dos\r\nlast\r"[..]).unwrap();
+        assert_eq!(read, lines(&["dos\r", "last\r"]));
+        assert_eq!(bytes, 10);
+    }
+
+    #[test]
+    fn marks_follow_their_line() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "c", "d", "e"]));
+        let mut config = Config::default();
+        let mark = Command::MarkLine(Some(Addr::number(4)), 'a');
+        assert!(mark.run(&mut buffer, &mut config).is_ok());
+
+        let first = Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, None));
+        assert!(Command::Delete(first).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(Addr::mark('a').resolve(&buffer, &mut config).unwrap(), 2);
+
+        let marked = || Some(LineRange::new(Some(Addr::mark('a')), Mode::Comma, None));
+        let command = Command::MoveLines(marked(), Some(Addr::number(0)));
+        assert!(command.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(Addr::mark('a').resolve(&buffer, &mut config).unwrap(), 0);
+        assert_eq!(buffer, lines(&["d", "b", "c", "e"]));
+
+        assert!(Command::Delete(marked()).run(&mut buffer, &mut config).is_ok());
+        assert!(Addr::mark('a').resolve(&buffer, &mut config).is_err());
+    }
+
+    #[test]
+    fn undo_reverts_a_whole_change() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three"]));
+        let mut config = Config {
+            current_index: Some(2),
+            // the replacement text for `c`, as if typed by the user
+            command_list: Some(lines(&["new 1", "new 2", "new 3", "."]).into()),
+            ..Default::default()
+        };
+        let res = Command::ChangeText(Some(LineRange::everything())).run(&mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["new 1", "new 2", "new 3"]));
+
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["one", "two", "three"]));
+        assert_eq!(config.current_index, Some(2));
+
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["new 1", "new 2", "new 3"]));
+    }
+
+    #[test]
+    fn undo_and_redo_several_steps() {
+        let mut buffer = Buffer::from(lines(&["a"]));
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        for replacement in &["b", "c", "d"] {
+            let command = Command::Substitute(None, ".".into(), replacement.to_string(),
+                                              SubstFlags::default());
+            assert!(command.run(&mut buffer, &mut config).is_ok());
+        }
+        assert!(Command::UndoSteps(2).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["b"]));
+        assert!(Command::RedoSteps(1).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["c"]));
+        assert!(Command::UndoSteps(5).run(&mut buffer, &mut config).is_err());
+        assert_eq!(buffer, lines(&["a"]));
+    }
+
+    #[test]
+    fn insert_puts_dot_on_the_last_inserted_line() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three"]));
+        let mut config = Config {
+            current_index: Some(2),
+            command_list: Some(lines(&["new", "."]).into()),
+            ..Default::default()
+        };
+        let res = Command::InsertText(Some(Addr::number(2))).run(&mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["one", "new", "two", "three"]));
+        assert_eq!(config.current_index, Some(1));
+
+        config.command_list = Some(lines(&["zero", "."]).into());
+        let res = Command::InsertText(Some(Addr::number(0))).run(&mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer[0], "zero");
+        assert_eq!(config.current_index, Some(0));
+    }
+
+    #[test]
+    fn commands_needing_a_line_reject_address_zero() {
+        let mut buffer = Buffer::from(lines(&["one"]));
+        let mut config = Config::default();
+        let zero = Some(LineRange::new(Some(Addr::number(0)), Mode::Comma, None));
+        assert!(Command::Delete(zero.clone()).run(&mut buffer, &mut config).is_err());
+        assert!(Command::Print(zero).run(&mut buffer, &mut config).is_err());
+        assert!(Command::MarkLine(Some(Addr::number(0)), 'a').run(&mut buffer, &mut config).is_err());
+        assert_eq!(buffer, lines(&["one"]));
+    }
+
+    #[test]
+    fn delete_at_end_moves_dot_to_new_last_line() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three"]));
+        let mut config = Config::default();
+        let range = Some(LineRange::new(Some(Addr::number(2)), Mode::Comma, Some(Addr::dollar_sign())));
+        assert!(Command::Delete(range).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(0));
+    }
+
+    #[test]
+    fn join_defaults_to_current_and_next_line() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three", "four"]));
+        let mut config = Config {
+            current_index: Some(1),
+            ..Default::default()
+        };
+        assert!(Command::JoinLines(None).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["one", "twothree", "four"]));
+        assert_eq!(config.current_index, Some(1));
+        assert!(config.dirty);
+
+        let range = Some(LineRange::everything());
+        assert!(Command::JoinLines(range).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["onetwothreefour"]));
+        assert_eq!(config.current_index, Some(0));
+
+        // nothing after the last line to join it with
+        assert!(Command::JoinLines(None).run(&mut buffer, &mut config).is_err());
+
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["one", "twothree", "four"]));
+    }
+
+    #[test]
+    fn join_single_line_changes_nothing() {
+        let mut buffer = Buffer::from(lines(&["one", "two"]));
+        let mut config = Config {
+            current_index: Some(1),
+            ..Default::default()
+        };
+        let range = Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, None));
+        assert!(Command::JoinLines(range).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["one", "two"]));
+        assert_eq!(config.current_index, Some(1));
+        assert!(!config.dirty);
+    }
+
+    #[test]
+    fn move_lines_up_down_and_to_the_top() {
+        let mut buffer = Buffer::from(lines(&["1", "2", "3", "4", "5"]));
+        let mut config = Config::default();
+        let range = |s, e| Some(LineRange::new(Some(Addr::number(s)), Mode::Comma, Some(Addr::number(e))));
+
+        assert!(Command::MoveLines(range(1, 2), Some(Addr::number(4))).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["3", "4", "1", "2", "5"]));
+        assert_eq!(config.current_index, Some(3));
+
+        assert!(Command::MoveLines(range(4, 5), Some(Addr::number(0))).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["2", "5", "3", "4", "1"]));
+        assert_eq!(config.current_index, Some(1));
+
+        // moving lines to just after themselves changes nothing
+        assert!(Command::MoveLines(range(2, 3), Some(Addr::number(3))).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["2", "5", "3", "4", "1"]));
+
+        assert!(Command::MoveLines(range(2, 4), Some(Addr::number(3))).run(&mut buffer, &mut config).is_err());
+        assert_eq!(buffer, lines(&["2", "5", "3", "4", "1"]));
+    }
+
+    #[test]
+    fn transfer_copies_after_the_destination() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "c"]));
+        let mut config = Config::default();
+        let range = Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, Some(Addr::number(2))));
+
+        assert!(Command::Transfer(range.clone(), Some(Addr::number(2))).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "b", "a", "b", "c"]));
+        assert_eq!(config.current_index, Some(3));
+
+        assert!(Command::Transfer(range, Some(Addr::number(0))).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "b", "a", "b", "a", "b", "c"]));
+        assert_eq!(config.current_index, Some(1));
+        assert!(config.dirty);
+    }
+
+    #[test]
+    fn print_suffix_runs_the_command_and_is_undone_with_it() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three"]));
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let command = Command::Delete(None).with_suffix(PrintFlags::PRINT);
+        assert!(command.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["two", "three"]));
+        assert_eq!(config.current_index, Some(0));
+
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["one", "two", "three"]));
+
+        // there is no `.` to print after deleting everything
+        let command = Command::Delete(Some(LineRange::everything())).with_suffix(PrintFlags::LIST);
+        assert!(command.run(&mut buffer, &mut config).is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn yank_and_put_through_the_cut_buffer() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "c"]));
+        let mut config = Config::default();
+        assert!(Command::InsertFromCut(None).run(&mut buffer, &mut config).is_err());
+
+        let range = Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, Some(Addr::number(2))));
+        assert!(Command::YankToCut(range).run(&mut buffer, &mut config).is_ok());
+        assert!(Command::InsertFromCut(Some(Addr::dollar_sign())).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "b", "c", "a", "b"]));
+        assert_eq!(config.current_index, Some(4));
+
+        assert!(Command::InsertFromCut(Some(Addr::number(0))).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "b", "a", "b", "c", "a", "b"]));
+        assert_eq!(config.current_index, Some(1));
+    }
+
+    #[test]
+    fn removing_commands_fill_the_cut_buffer() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three", "four"]));
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        assert!(Command::Delete(None).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.cut_buffer, lines(&["one"]));
+
+        assert!(Command::JoinLines(None).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.cut_buffer, lines(&["two", "three"]));
+
+        let range = Some(LineRange::everything());
+        let res = Command::Substitute(range, "o".into(), "0".into(), SubstFlags::default()).run(&mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(config.cut_buffer, lines(&["twothree", "four"]));
+
+        config.command_list = Some(lines(&["new", "."]).into());
+        assert!(Command::ChangeText(None).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.cut_buffer, lines(&["f0ur"]));
+        assert_eq!(buffer, lines(&["tw0three", "new"]));
+    }
+
+    #[test]
+    fn writing_to_a_command_leaves_the_buffer_unsaved() {
+        let mut buffer = Buffer::from(lines(&["a", "b"]));
+        let mut config = Config {
+            dirty: true,
+            ..Default::default()
+        };
+        let range = Some(LineRange::new(Some(Addr::number(2)), Mode::Comma, None));
+        let res = Command::SaveFile(range, Some("!cat > /dev/null".into())).run(&mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert!(config.dirty);
+    }
+
+    #[test]
+    fn null_command_moves_to_and_prints_a_line() {
+        let mut buffer = Buffer::from(lines(&["one", "two", "three"]));
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        assert!(Command::NullCmd(None).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(1));
+
+        let range = Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, Some(Addr::dollar_sign())));
+        assert!(Command::NullCmd(range).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(2));
+        assert!(Command::NullCmd(None).run(&mut buffer, &mut config).is_err());
+
+        let range = Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, None));
+        assert!(Command::Comment(range).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(0));
+        assert!(Command::Comment(None).run(&mut buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(0));
+    }
+
+    #[test]
+    fn script_cannot_quit_with_unsaved_changes() {
+        let mut buffer = Buffer::from(lines(&["a"]));
+        let mut config = Config {
+            interactive: false,
+            dirty: true,
+            ..Default::default()
+        };
+        match Command::Quit.run(&mut buffer, &mut config) {
+            Err(Error(ErrorKind::Msg(msg), _)) => assert_eq!(msg, "Warning: buffer modified"),
+            res => panic!("expected a warning, got {:?}", res),
         }
+        config.dirty = false;
+        match Command::Quit.run(&mut buffer, &mut config) {
+            Err(Error(ErrorKind::Exit, _)) => (),
+            res => panic!("expected to exit, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn load_file_sets_the_default_filename_even_if_missing() {
+        use super::load_file;
+
+        let mut buffer = Buffer::new();
+        let mut config = Config::default();
+        assert!(load_file("/nonexistent/rusted-test", &mut buffer, &mut config).is_err());
+        assert_eq!(config.default_filename, Some("/nonexistent/rusted-test".into()));
+
+        let bytes = load_file("!printf 'a\\nbc\\n'", &mut buffer, &mut config).unwrap();
+        assert_eq!(bytes, 5);
+        assert_eq!(buffer, lines(&["a", "bc"]));
+        assert_eq!(config.current_index, Some(1));
+        assert_eq!(config.default_filename, Some("/nonexistent/rusted-test".into()));
+    }
+
+    #[test]
+    fn reads_and_writes_return_byte_counts() {
+        use std::fs::OpenOptions;
+        use super::{edit_file, save_file};
+        use TempFile;
+
+        let file = TempFile::new("byte-count");
+        let path = file.path();
+        let filename = Some(path.to_string_lossy().into_owned());
+        let mut buffer = Buffer::from(lines(&["héllo", "", "world"]));
+        let mut config = Config {
+            interactive: false,
+            dirty: true,
+            ..Default::default()
+        };
+
+        let mut oo = OpenOptions::new();
+        let bytes = save_file(0, 3, oo.write(true), filename.clone(), &mut buffer, &mut config);
+        assert_eq!(bytes.unwrap(), 14);
+        assert!(!config.dirty);
+
+        let mut oo = OpenOptions::new();
+        let bytes = save_file(2, 3, oo.append(true), filename, &mut buffer, &mut config);
+        assert_eq!(bytes.unwrap(), 6);
+
+        let bytes = edit_file(path, &mut buffer, &mut config);
+        assert_eq!(bytes.unwrap(), 20);
+        assert_eq!(buffer, lines(&["héllo", "", "world", "world"]));
+    }
+
+    #[test]
+    fn global_is_undone_as_one_change() {
+        let mut buffer = Buffer::from(lines(&["a", "b", "a"]));
+        let mut config = Config::default();
+        let res = Command::Global(None, "a".into(), "d".into()).run(&mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["b"]));
+        assert!(Command::Undo.run(&mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "b", "a"]));
     }
 }
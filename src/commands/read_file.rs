@@ -0,0 +1,57 @@
+use parse::Addr;
+use commands::read_file;
+use {Buffer, Config};
+use errors::*;
+
+/// Insert the contents of `filename` (or the output of a shell command, for
+/// `r !command`) after `line`, returning how many bytes were read.
+pub fn cmd(line: Option<Addr>, filename: Option<String>,
+           buffer: &mut Buffer, cfg: &mut Config) -> Result<usize> {
+    let position = line.unwrap_or(Addr::dollar_sign())
+                       .line_number(buffer, cfg)?;
+    read_file(position, filename, buffer, cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use super::cmd;
+    use parse::Addr;
+    use {lines, Buffer, Config, TempFile};
+
+    #[test]
+    fn reads_file_after_line_and_sets_default_filename() {
+        let file = TempFile::new("read-file");
+        let path = file.path();
+        {
+            let mut fp = File::create(path).unwrap();
+            write!(fp, "x\ny\n").unwrap();
+        }
+        let mut buffer = Buffer::from(lines(&["a", "b"]));
+        let mut config = Config::default();
+        let filename = Some(path.to_string_lossy().into_owned());
+        let res = cmd(Some(Addr::number(1)), filename, &mut buffer, &mut config);
+        assert_eq!(res.unwrap(), 4);
+        assert_eq!(buffer, lines(&["a", "x", "y", "b"]));
+        assert_eq!(config.current_index, Some(2));
+        assert_eq!(config.default_filename, Some(path.to_path_buf()));
+
+        // with no filename, the default is read, after `$`
+        assert!(cmd(None, None, &mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, lines(&["a", "x", "y", "b", "x", "y"]));
+    }
+
+    #[test]
+    fn reads_shell_command_output() {
+        let mut buffer = Buffer::from(lines(&["a"]));
+        let mut config = Config::default();
+        let res = cmd(Some(Addr::number(0)), Some("!printf 'one\\ntwo\\n'".into()),
+                      &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, lines(&["one", "two", "a"]));
+        assert_eq!(config.current_index, Some(1));
+        assert!(config.default_filename.is_none());
+    }
+}
@@ -0,0 +1,71 @@
+use std::env;
+
+use parse::Addr;
+use commands::{print_line, unknown, PrintFlags};
+use {Buffer, Config};
+use errors::*;
+
+/// The terminal height to assume when `LINES` isn't set
+const DEFAULT_HEIGHT: usize = 24;
+
+/// The height of the terminal, from `LINES`.
+fn terminal_height() -> usize {
+    env::var("LINES").ok()
+                     .and_then(|l| l.parse().ok())
+                     .filter(|&h| h > 0)
+                     .unwrap_or(DEFAULT_HEIGHT)
+}
+
+/// Print a window of `count` lines starting at `line` (`.+1` by default). A
+/// count becomes the window size for later `z`s; until one is given, the
+/// window fills the terminal, leaving room for the prompt.
+pub fn cmd(line: Option<Addr>, count: Option<usize>, flags: PrintFlags,
+           buffer: &Buffer, cfg: &mut Config) -> Result<()> {
+    if buffer.is_empty() {
+        return Err(unknown());
+    }
+    let start = line.unwrap_or(Addr::next_line()).resolve(buffer, cfg)?;
+    if let Some(count) = count {
+        cfg.window_lines = Some(count);
+    }
+    let count = cfg.window_lines
+                   .unwrap_or_else(|| terminal_height().saturating_sub(1))
+                   .max(1);
+    let end = (start + count).min(buffer.len());
+    for idx in start..end {
+        print_line(buffer, idx, flags);
+    }
+    cfg.current_index = Some(end - 1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cmd;
+    use commands::PrintFlags;
+    use parse::Addr;
+    use {Buffer, Config};
+
+    fn buffer(len: usize) -> Buffer {
+        Buffer::from((1..len + 1).map(|n| n.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn pages_forward_from_the_next_line() {
+        let buffer = buffer(10);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        assert!(cmd(None, Some(3), PrintFlags::PRINT, &buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(3));
+        assert_eq!(config.window_lines, Some(3));
+
+        // the window size is remembered, and stops at the end of the buffer
+        assert!(cmd(Some(Addr::number(9)), None, PrintFlags::PRINT, &buffer, &mut config).is_ok());
+        assert_eq!(config.current_index, Some(9));
+
+        // no lines after the last one
+        assert!(cmd(None, None, PrintFlags::PRINT, &buffer, &mut config).is_err());
+    }
+}
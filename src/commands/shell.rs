@@ -0,0 +1,162 @@
+use std::io::{self, Write};
+use std::process::{self, Stdio};
+
+use commands::read_lines;
+use Config;
+use errors::*;
+
+/// Run `command` with the shell, as `!command` does, and write `!` once it
+/// has finished.
+pub fn cmd(command: &str, cfg: &mut Config) -> Result<()> {
+    let (command, expanded) = expand(command, cfg)?;
+    if expanded && !cfg.silent {
+        let _ = writeln!(&mut io::stdout(), "{}", command);
+    }
+    let _ = io::stdout().flush();
+    let res = process::Command::new("sh").arg("-c").arg(&command).status();
+    cfg.last_shell_command = Some(command);
+    if res.is_err() {
+        return Err("Could not run command".into());
+    }
+    if !cfg.silent {
+        let _ = writeln!(&mut io::stdout(), "!");
+        let _ = io::stdout().flush();
+    }
+    Ok(())
+}
+
+/// Pipe `lines` into `command`, as `w !command` does, returning the number
+/// of bytes written.
+pub fn write_to_command(lines: &[String], command: &str, cfg: &mut Config) -> Result<usize> {
+    let (command, expanded) = expand(command, cfg)?;
+    if expanded && !cfg.silent {
+        let _ = writeln!(&mut io::stdout(), "{}", command);
+        let _ = io::stdout().flush();
+    }
+    let mut child = match process::Command::new("sh").arg("-c").arg(&command)
+                                                .stdin(Stdio::piped())
+                                                .spawn() {
+        Ok(c) => c,
+        Err(_) => return Err("Could not run command".into()),
+    };
+    cfg.last_shell_command = Some(command);
+    let mut bytes = 0;
+    if let Some(mut stdin) = child.stdin.take() {
+        for line in lines {
+            // the command may exit without reading everything
+            if writeln!(stdin, "{}", line).is_err() {
+                break;
+            }
+            bytes += line.len() + 1;
+        }
+    }
+    if child.wait().is_err() {
+        return Err("Could not run command".into());
+    }
+    Ok(bytes)
+}
+
+/// Run `command`, as `r !command` does, returning the lines it writes to
+/// stdout and the number of bytes read.
+pub fn read_from_command(command: &str, cfg: &mut Config) -> Result<(Vec<String>, usize)> {
+    let (command, expanded) = expand(command, cfg)?;
+    if expanded && !cfg.silent {
+        let _ = writeln!(&mut io::stdout(), "{}", command);
+        let _ = io::stdout().flush();
+    }
+    let output = match process::Command::new("sh").arg("-c").arg(&command).output() {
+        Ok(o) => o,
+        Err(_) => return Err("Could not run command".into()),
+    };
+    cfg.last_shell_command = Some(command);
+    read_lines(&output.stdout[..])
+}
+
+/// Replace a leading `!` with the previous shell command and an unescaped
+/// `%` with the default filename, returning the new command and whether
+/// anything was replaced.
+pub fn expand(command: &str, cfg: &Config) -> Result<(String, bool)> {
+    let mut out = String::with_capacity(command.len());
+    let mut expanded = false;
+    let mut chars = command.chars().peekable();
+    if let Some(&'!') = chars.peek() {
+        chars.next();
+        match cfg.last_shell_command {
+            Some(ref previous) => out.push_str(previous),
+            None => return Err("No previous command".into()),
+        }
+        expanded = true;
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('%') => out.push('%'),
+                Some(e) => {
+                    out.push('\\');
+                    out.push(e);
+                },
+                None => out.push('\\'),
+            },
+            '%' => {
+                match cfg.default_filename {
+                    Some(ref f) => out.push_str(&f.to_string_lossy()),
+                    None => return Err("No current filename".into()),
+                }
+                expanded = true;
+            },
+            _ => out.push(c),
+        }
+    }
+    Ok((out, expanded))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use super::{expand, read_from_command, write_to_command};
+    use {Config, TempFile};
+
+    #[test]
+    fn reads_the_output_of_an_expanded_command() {
+        let mut config = Config {
+            default_filename: Some("name".into()),
+            silent: true,
+            ..Default::default()
+        };
+        let (read, bytes) = read_from_command("echo %", &mut config).unwrap();
+        assert_eq!(read, vec!["name".to_string()]);
+        assert_eq!(bytes, 5);
+        assert_eq!(config.last_shell_command, Some("echo name".into()));
+    }
+
+    #[test]
+    fn pipes_lines_into_a_command() {
+        let file = TempFile::new("write-to-command");
+        let path = file.path();
+        let mut config = Config::default();
+        let lines = vec!["b".to_string(), "a".into()];
+        let command = format!("sort > {}", path.display());
+        assert_eq!(write_to_command(&lines, &command, &mut config).unwrap(), 4);
+        assert_eq!(config.last_shell_command, Some(command));
+
+        let mut written = String::new();
+        File::open(path).unwrap().read_to_string(&mut written).unwrap();
+        assert_eq!(written, "a\nb\n");
+    }
+
+    #[test]
+    fn expands_filename_and_previous_command() {
+        let mut config = Config::default();
+        assert_eq!(expand("ls -l", &config).unwrap(), ("ls -l".into(), false));
+        assert!(expand("wc %", &config).is_err());
+        assert!(expand("!", &config).is_err());
+
+        config.default_filename = Some("notes.txt".into());
+        assert_eq!(expand("wc % \\%", &config).unwrap(), ("wc notes.txt %".into(), true));
+
+        config.last_shell_command = Some("wc notes.txt".into());
+        assert_eq!(expand("! | sort", &config).unwrap(), ("wc notes.txt | sort".into(), true));
+    }
+}
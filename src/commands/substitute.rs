@@ -0,0 +1,250 @@
+use regex::{Captures, Regex};
+
+use commands::{insert_all, unknown, RepeatFlags, SubstFlags, Substitution};
+use {Buffer, Config};
+use parse::LineRange;
+use pattern;
+use errors::*;
+
+/// Replace matches of `regex` in `range` (`.` by default). A replacement of
+/// just `%` is the one used last time. Matching nothing is only an error
+/// outside of a global command.
+pub fn cmd(range: Option<LineRange>, regex: &str, replacement: &str, flags: SubstFlags,
+           in_global: bool, buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+    if buffer.is_empty() {
+        return Err(unknown());
+    }
+    let range = range.unwrap_or(LineRange::current_line())
+                     .resolve(buffer, cfg)?;
+    let re = pattern::compile_or_last(regex, cfg)?;
+    let replacement = if replacement == "%" {
+        match cfg.last_substitution {
+            Some(ref last) => last.replacement.clone(),
+            None => return Err("No previous substitution".into()),
+        }
+    } else {
+        replacement.to_owned()
+    };
+    cfg.last_substitution = Some(Substitution {
+        regex: cfg.last_regex.clone().unwrap_or_default(),
+        replacement: replacement.clone(),
+        flags: flags.clone(),
+    });
+    let replacement = &replacement[..];
+    let (mut idx, mut end) = range;
+    let mut last_changed = None;
+    let mut originals = vec![];
+    while idx <= end {
+        let replaced = match substitute_line(&re, &buffer[idx], replacement, &flags) {
+            Some(r) => r,
+            None => {
+                idx += 1;
+                continue;
+            },
+        };
+        let mut lines = replaced.split('\n').map(|l| l.to_owned()).collect::<Vec<_>>();
+        let rest = lines.split_off(1);
+        originals.push(buffer[idx].clone());
+        buffer.set(idx, lines.remove(0));
+        insert_all(buffer, idx + 1, &rest)?;
+        idx += rest.len() + 1;
+        end += rest.len();
+        last_changed = Some(idx - 1);
+    }
+    match last_changed {
+        Some(line) => {
+            cfg.current_index = Some(line);
+            cfg.cut_buffer = originals;
+            cfg.dirty = true;
+            Ok(())
+        },
+        None if in_global => Ok(()),
+        None => Err("No match".into()),
+    }
+}
+
+/// Repeat the last substitution on `range`, with its flags changed by `flags`.
+pub fn repeat(range: Option<LineRange>, flags: RepeatFlags, in_global: bool,
+              buffer: &mut Buffer, cfg: &mut Config) -> Result<()> {
+    let last = match cfg.last_substitution {
+        Some(ref last) => last.clone(),
+        None => return Err("No previous substitution".into()),
+    };
+    let regex = if flags.last_regex {
+        match cfg.last_regex {
+            Some(ref re) => re.clone(),
+            None => return Err("No previous pattern".into()),
+        }
+    } else {
+        last.regex
+    };
+    let subst_flags = SubstFlags {
+        global: last.flags.global != flags.toggle_global,
+        nth: flags.nth.unwrap_or(last.flags.nth),
+    };
+    cmd(range, &regex, &last.replacement, subst_flags, in_global, buffer, cfg)
+}
+
+/// Apply the substitution to a single line, returning `None` if nothing was
+/// replaced.
+fn substitute_line(re: &Regex, line: &str, replacement: &str,
+                   flags: &SubstFlags) -> Option<String> {
+    let mut out = String::with_capacity(line.len());
+    let mut last_end = 0;
+    let mut replaced = false;
+    for (n, caps) in re.captures_iter(line).enumerate() {
+        let n = n + 1;
+        if n < flags.nth {
+            continue;
+        }
+        let whole = caps.get(0).unwrap();
+        out.push_str(&line[last_end..whole.start()]);
+        expand(replacement, &caps, &mut out);
+        last_end = whole.end();
+        replaced = true;
+        if !flags.global {
+            break;
+        }
+    }
+    if !replaced {
+        return None;
+    }
+    out.push_str(&line[last_end..]);
+    Some(out)
+}
+
+/// Expand `&` and `\1` through `\9` in `replacement`. An escaped newline
+/// becomes a real one, which splits the line.
+fn expand(replacement: &str, caps: &Captures, out: &mut String) {
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => out.push_str(caps.get(0).map_or("", |m| m.as_str())),
+            '\\' => match chars.next() {
+                Some(d @ '1'..='9') => {
+                    let group = d.to_digit(10).unwrap() as usize;
+                    out.push_str(caps.get(group).map_or("", |m| m.as_str()));
+                },
+                Some(e) => out.push(e),
+                None => out.push('\\'),
+            },
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cmd, repeat};
+    use commands::{RepeatFlags, SubstFlags};
+    use parse::LineRange;
+    use {Buffer, Config};
+
+    fn flags(global: bool, nth: usize) -> SubstFlags {
+        SubstFlags { global, nth }
+    }
+
+    #[test]
+    fn replaces_first_match_on_current_line() {
+        let mut buffer = Buffer::from(vec!["foo foo".into(), "foo".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let res = cmd(None, "foo", "bar", flags(false, 1), false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, vec!["bar foo".to_string(), "foo".into()]);
+        assert!(config.dirty);
+    }
+
+    #[test]
+    fn global_and_nth_occurrence() {
+        let mut buffer = Buffer::from(vec!["a a a a".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let _ = cmd(None, "a", "b", flags(false, 2), false, &mut buffer, &mut config);
+        assert_eq!(buffer, vec!["a b a a".to_string()]);
+        let _ = cmd(None, "a", "c", flags(true, 2), false, &mut buffer, &mut config);
+        assert_eq!(buffer, vec!["a b c c".to_string()]);
+    }
+
+    #[test]
+    fn backreferences_and_ampersand() {
+        let mut buffer = Buffer::from(vec!["hello world".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let res = cmd(None, r"\(hello\) \(world\)", r"\2 \1 [&] \&",
+                      flags(false, 1), false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        assert_eq!(buffer, vec!["world hello [hello world] &".to_string()]);
+    }
+
+    #[test]
+    fn escaped_newline_splits_the_line() {
+        let mut buffer = Buffer::from(vec!["one two".to_string(), "three four".into()]);
+        let mut config = Config::default();
+        let range = Some(LineRange::everything());
+        let res = cmd(range, " ", "\\\n", flags(false, 1), false, &mut buffer, &mut config);
+        assert!(res.is_ok());
+        let expected: Vec<String> = vec!["one".into(), "two".into(),
+                                         "three".into(), "four".into()];
+        assert_eq!(buffer, expected);
+        assert_eq!(config.current_index, Some(3));
+    }
+
+    #[test]
+    fn no_match_is_an_error_and_empty_pattern_reuses_last() {
+        let mut buffer = Buffer::from(vec!["abc".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        assert!(cmd(None, "x", "y", flags(false, 1), false, &mut buffer, &mut config).is_err());
+        assert!(!config.dirty);
+        config.last_regex = Some("b".into());
+        assert!(cmd(None, "", "x", flags(false, 1), false, &mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, vec!["axc".to_string()]);
+    }
+
+    #[test]
+    fn repeats_the_last_substitution() {
+        let mut buffer = Buffer::from(vec!["a a".into(), "a a".into(), "b".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        assert!(repeat(None, RepeatFlags::default(), false, &mut buffer, &mut config).is_err());
+
+        assert!(cmd(None, "a", "x", flags(false, 1), false, &mut buffer, &mut config).is_ok());
+        config.current_index = Some(1);
+        let toggled = RepeatFlags { toggle_global: true, ..Default::default() };
+        assert!(repeat(None, toggled, false, &mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, vec!["x a".to_string(), "x x".into(), "b".into()]);
+
+        // `r` uses the last regex from anywhere, here a search for `b`
+        config.last_regex = Some("b".into());
+        let last_regex = RepeatFlags { last_regex: true, ..Default::default() };
+        let range = Some(LineRange::everything());
+        assert!(repeat(range, last_regex, false, &mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, vec!["x a".to_string(), "x x".into(), "x".into()]);
+    }
+
+    #[test]
+    fn percent_replacement_is_the_previous_one() {
+        let mut buffer = Buffer::from(vec!["one two".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        assert!(cmd(None, "one", "%", flags(false, 1), false, &mut buffer, &mut config).is_err());
+        assert!(cmd(None, "one", "1", flags(false, 1), false, &mut buffer, &mut config).is_ok());
+        assert!(cmd(None, "two", "%", flags(false, 1), false, &mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, vec!["1 1".to_string()]);
+        assert!(cmd(None, "1", "\\%", flags(false, 1), false, &mut buffer, &mut config).is_ok());
+        assert_eq!(buffer, vec!["% 1".to_string()]);
+    }
+}
@@ -1,18 +1,19 @@
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate nom;
 #[macro_use] extern crate log;
+extern crate regex;
 #[cfg(test)]
 #[macro_use] extern crate nom_test_helpers;
 
 use std::io::{self, Write};
 use std::default::Default;
-use std::collections::HashMap;
-use std::process;
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
 
 use errors::*;
 
-const DEFAULT_PROMPT: &'static str = "*";
+const DEFAULT_PROMPT: &str = "*";
+const DEFAULT_UNDO_LEVELS: usize = 100;
 
 pub struct Config {
     pub prompt: String,
@@ -21,9 +22,24 @@ pub struct Config {
     pub current_index: Option<usize>,
     pub default_filename: Option<PathBuf>,
     pub cut_buffer: Vec<String>,
+    /// the ids of the lines marked with `k`
     pub marks: HashMap<char, usize>,
     pub last_error: Option<String>,
+    pub last_regex: Option<String>,
+    pub last_substitution: Option<commands::Substitution>,
     pub print_errors: bool,
+    pub command_list: Option<VecDeque<String>>,
+    /// how many changes are remembered for undo
+    pub undo_levels: usize,
+    /// how many lines `z` prints, once it has been told
+    pub window_lines: Option<usize>,
+    /// the last command run with `!`, for `!!`
+    pub last_shell_command: Option<String>,
+    /// `-s`: don't print byte counts, or the `!` after a shell command
+    pub silent: bool,
+    /// whether commands are being typed, rather than read from a script,
+    /// which stops at the first error
+    pub interactive: bool,
 }
 
 impl Default for Config {
@@ -37,51 +53,92 @@ impl Default for Config {
             cut_buffer: vec![],
             marks: HashMap::new(),
             last_error: None,
+            last_regex: None,
+            last_substitution: None,
             print_errors: false,
+            command_list: None,
+            undo_levels: DEFAULT_UNDO_LEVELS,
+            window_lines: None,
+            last_shell_command: None,
+            silent: false,
+            interactive: true,
         }
     }
 }
 
-impl Config {
-    pub fn update_curidx(&mut self, idx: usize) {
-        let loc = if let Some(v) = self.current_index.as_ref() {
-            *v + idx
-        } else {
-            idx
-        };
-        self.current_index = Some(loc);
-    }
-}
-
-pub type Buffer = Vec<String>;
+pub use buffer::Buffer;
+pub use commands::{edit_file, load_file, read_file, save_file, SubstFlags, Substitution};
 
 pub fn insert_all(buffer: &mut Buffer, index: usize, elements: &[String]) -> Result<()> {
-    for (idx, elem) in elements.into_iter().enumerate() {
+    for (idx, elem) in elements.iter().enumerate() {
         buffer.insert(index + idx, elem.to_owned());
     }
     Ok(())
 }
 
-pub fn run(config: &mut Config) -> Result<()> {
+/// Owned lines for building and checking buffers in tests
+#[cfg(test)]
+fn lines(l: &[&str]) -> Vec<String> {
+    l.iter().map(|s| s.to_string()).collect()
+}
+
+/// A file in the temp dir for a test, named after the test and the process so
+/// that test runs don't share it, and removed again however the test ends.
+#[cfg(test)]
+struct TempFile(PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    fn new(test: &str) -> TempFile {
+        let name = format!("rusted-{}-{}", test, std::process::id());
+        TempFile(std::env::temp_dir().join(name))
+    }
+
+    fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Run the editor, starting with the contents of `file` (or the output of a
+/// shell command, for `!command`) if one is given.
+pub fn run(config: &mut Config, file: Option<String>) -> Result<()> {
     let mut buffer = Buffer::new();
+    if let Some(file) = file {
+        // a file that can't be read still becomes the default filename, and
+        // the editor carries on with an empty buffer
+        match commands::load_file(&file, &mut buffer, config) {
+            Ok(bytes) => commands::print_count(bytes, config),
+            Err(e) => {
+                println!("?");
+                eprintln!("{}: {}", file, e);
+                config.last_error = Some(e.to_string());
+            },
+        }
+    }
     loop {
         if config.show_prompt {
             write!(&mut io::stdout(), "{}", config.prompt)
                                 .chain_err(|| "Couldn't write prompt")?;
         }
         io::stdout().flush().chain_err(|| "Couldn't flush stdout")?;
-        let mut inp = String::new();
-        io::stdin().read_line(&mut inp).chain_err(|| "Couldn't read input")?;
-        let inp = parse::parse_line(inp.trim());
-        let inp = match inp {
-            nom::IResult::Done(_, o) => o,
-            x => {
-                debug!("Not done, got {:?}", x);
-                continue;
-            },
+        let inp = match commands::read_command(config) {
+            Some(inp) => inp,
+            None => return Ok(()),
         };
-        debug!("Command: {:?}, current index: {:?}", &inp, config.current_index);
-        match inp.run(&mut buffer, config) {
+        let res = parse::parse_command(inp.trim()).and_then(|command| {
+            debug!("Command: {:?}, current index: {:?}", &command, config.current_index);
+            command.run(&mut buffer, config)
+        });
+        match res {
+            Ok(()) => continue,
+            Err(Error(ErrorKind::Exit, _)) => return Ok(()),
             Err(Error(ErrorKind::Unknown, _)) => {
                 println!("?");
             },
@@ -93,13 +150,15 @@ pub fn run(config: &mut Config) -> Result<()> {
                 }
                 config.last_error = Some(s);
             },
-            Err(_) => process::exit(1),
-            _ => (),
         };
+        if !config.interactive {
+            return Err("Error in script".into());
+        }
     }
 }
 
+mod buffer;
 mod errors;
 mod parse;
-mod cli;
+mod pattern;
 mod commands;
@@ -1,9 +1,11 @@
-use nom;
+use nom::{self, IResult};
+use std::convert::TryFrom;
 use std::str;
 
 use errors::*;
 use {Buffer, Config};
-use commands::Command;
+use commands::{Command, PrintFlags, RepeatFlags, SubstFlags};
+use pattern;
 
 fn lowercase() -> String {
     (97u8..123).map(|b| b as char)
@@ -13,20 +15,84 @@ fn lowercase() -> String {
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct LineRange(Option<Addr>, Mode, Option<Addr>);
+pub struct LineRange {
+    /// any addresses before the last two, which only matter because `;`
+    /// sets `.`
+    leading: Vec<(Addr, Mode)>,
+    start: Option<Addr>,
+    mode: Mode,
+    end: Option<Addr>,
+}
 
 impl LineRange {
+    pub fn new(start: Option<Addr>, mode: Mode, end: Option<Addr>) -> LineRange {
+        LineRange {
+            leading: vec![],
+            start,
+            mode,
+            end,
+        }
+    }
+
     pub fn everything() -> LineRange {
-        LineRange(Some(Addr::number(1)), Mode::Comma, Some(Addr::dollar_sign()))
+        LineRange::new(Some(Addr::number(1)), Mode::Comma, Some(Addr::dollar_sign()))
     }
 
     pub fn current_line() -> LineRange {
-        LineRange(Some(Addr::period()), Mode::Comma, Some(Addr::period()))
+        LineRange::new(Some(Addr::period()), Mode::Comma, Some(Addr::period()))
+    }
+
+    /// `.,.+1`, the default for `j`
+    pub fn current_and_next() -> LineRange {
+        LineRange::new(Some(Addr::period()), Mode::Comma, Some(Addr::next_line()))
     }
 
-    pub fn resolve(self, buffer: &Buffer, config: &Config) -> Result<(usize, usize)> {
-        Ok((self.0.unwrap_or(Addr::number(1)).resolve(buffer, config)?,
-            self.2.unwrap_or(Addr::dollar_sign()).resolve(buffer, config)?))
+    /// The (0-based) indices of the first and last lines of the range, which
+    /// must be lines in the buffer, in order.
+    pub fn resolve(self, buffer: &Buffer, config: &mut Config) -> Result<(usize, usize)> {
+        let (start, end) = self.line_numbers(buffer, config)?;
+        if start == 0 || start > end {
+            return Err("Invalid address".into());
+        }
+        Ok((start - 1, end - 1))
+    }
+
+    /// The (1-based) first and last lines of the range.
+    ///
+    /// A missing first address is `1` after `,` and `.` after `;`, and a
+    /// missing second address is the same as the first, or `$` if both are
+    /// missing. With `;`, `.` is set to the first address before the second
+    /// is worked out.
+    pub fn line_numbers(self, buffer: &Buffer, config: &mut Config) -> Result<(usize, usize)> {
+        for (addr, mode) in self.leading {
+            let line = addr.line_number(buffer, config)?;
+            if mode == Mode::Semicolon {
+                set_current_line(line, config);
+            }
+        }
+        let has_start = self.start.is_some();
+        let start = match self.start {
+            Some(addr) => addr.line_number(buffer, config)?,
+            None => match self.mode {
+                Mode::Comma => 1.min(buffer.len()),
+                Mode::Semicolon => Addr::period().line_number(buffer, config)?,
+            },
+        };
+        if self.mode == Mode::Semicolon {
+            set_current_line(start, config);
+        }
+        let end = match self.end {
+            Some(addr) => addr.line_number(buffer, config)?,
+            None if has_start => start,
+            None => buffer.len(),
+        };
+        Ok((start, end))
+    }
+}
+
+fn set_current_line(line: usize, config: &mut Config) {
+    if line > 0 {
+        config.current_index = Some(line - 1);
     }
 }
 
@@ -36,71 +102,120 @@ pub enum LineAddr {
     DollarSign,
     Period,
     Mark(char),
+    /// `/re/`, the next line matching `re`
+    ForwardSearch(String),
+    /// `?re?`, the previous line matching `re`
+    BackwardSearch(String),
 }
 
+/// An offset following an address, such as the `+2` in `.+2`. `+` and `-` on
+/// their own mean an offset of one.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Modifier {
-    PrefixPlus,
     SuffixPlus(Option<u64>),
-    PrefixMinus,
     SuffixMinus(Option<u64>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Addr {
     pub primary: LineAddr,
-    modifier: Option<Modifier>,
+    modifiers: Vec<Modifier>,
 }
 
 impl Addr {
-    pub fn new(line_addr: LineAddr, modifier: Option<Modifier>) -> Addr {
+    pub fn new(line_addr: LineAddr, modifiers: Vec<Modifier>) -> Addr {
         Addr {
             primary: line_addr,
-            modifier: modifier,
+            modifiers,
         }
     }
 
-    pub fn resolve(self, buffer: &Buffer, config: &Config) -> Result<usize> {
-        Ok(match self.primary {
-            LineAddr::Number(n) => {
-                if n > 0 {
-                    (n - 1) as usize
-                } else {
-                    0
-                }
-            },
-            LineAddr::DollarSign => {
-                if buffer.len() > 1 {
-                    buffer.len() - 1
-                } else {
-                    0
-                }
-            },
-            LineAddr::Period => config.current_index.unwrap_or(0usize) as usize,
+    /// The (0-based) index of the line the address refers to, which must be a
+    /// line in the buffer.
+    pub fn resolve(self, buffer: &Buffer, config: &mut Config) -> Result<usize> {
+        match self.line_number(buffer, config)? {
+            0 => Err("Invalid address".into()),
+            line => Ok(line - 1),
+        }
+    }
+
+    /// The (1-based) line number the address refers to, with any offsets
+    /// applied. It is an error for it to be outside of the buffer, but it may
+    /// be `0`, for commands such as `a` that take the line *after* an address.
+    pub fn line_number(self, buffer: &Buffer, config: &mut Config) -> Result<usize> {
+        let invalid = || Error::from("Invalid address");
+        let base = match self.primary {
+            LineAddr::Number(n) => i64::try_from(n).map_err(|_| invalid())?,
+            LineAddr::DollarSign => buffer.len() as i64,
+            LineAddr::Period => config.current_index.map_or(0, |i| i as i64 + 1),
             LineAddr::Mark(s) => match config.marks.get(&s) {
-                Some(u) => *u,
-                None => return Err("Mark not found".into()), 
+                Some(&id) => match buffer.position(id, 0) {
+                    Some(idx) => idx as i64 + 1,
+                    // the marked line has been deleted
+                    None => return Err(invalid()),
+                },
+                None => return Err("Mark not found".into()),
             },
-        })
+            LineAddr::ForwardSearch(re) => search(&re, true, buffer, config)? as i64 + 1,
+            LineAddr::BackwardSearch(re) => search(&re, false, buffer, config)? as i64 + 1,
+        };
+        let line = self.modifiers.iter().try_fold(base, |line, modifier| {
+            let offset = match *modifier {
+                Modifier::SuffixPlus(n) | Modifier::SuffixMinus(n) => {
+                    i64::try_from(n.unwrap_or(1)).map_err(|_| invalid())?
+                },
+            };
+            match *modifier {
+                Modifier::SuffixPlus(_) => line.checked_add(offset),
+                Modifier::SuffixMinus(_) => line.checked_sub(offset),
+            }.ok_or_else(invalid)
+        })?;
+        if line < 0 || line > buffer.len() as i64 {
+            return Err(invalid());
+        }
+        Ok(line as usize)
     }
 
     pub fn number(num: u64) -> Addr {
-        Addr::new(LineAddr::Number(num), None)
+        Addr::new(LineAddr::Number(num), vec![])
     }
 
     pub fn dollar_sign() -> Addr {
-        Addr::new(LineAddr::DollarSign, None)
+        Addr::new(LineAddr::DollarSign, vec![])
     }
 
     pub fn period() -> Addr {
-        Addr::new(LineAddr::Period, None)
+        Addr::new(LineAddr::Period, vec![])
+    }
+
+    /// `.+1`
+    pub fn next_line() -> Addr {
+        Addr::new(LineAddr::Period, vec![Modifier::SuffixPlus(Some(1))])
     }
 
+    #[cfg(test)]
     pub fn mark(c: char) -> Addr {
-        Addr::new(LineAddr::Mark(c), None)
+        Addr::new(LineAddr::Mark(c), vec![])
     }
 }
 
+/// Find the next (or previous) line matching `regex`, starting from the line
+/// after (or before) `.` and wrapping around the end of the buffer.
+fn search(regex: &str, forward: bool, buffer: &Buffer, config: &mut Config) -> Result<usize> {
+    let re = pattern::compile_or_last(regex, config)?;
+    let len = buffer.len();
+    let current = config.current_index.unwrap_or(0);
+    (1..len + 1).map(|offset| {
+                    if forward {
+                        (current + offset) % len
+                    } else {
+                        (current + len - offset % len) % len
+                    }
+                })
+                .find(|&idx| re.is_match(&buffer[idx]))
+                .ok_or_else(|| "No match".into())
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mode {
     Comma,
@@ -140,51 +255,115 @@ named!(mark<&str, LineAddr>,
             (LineAddr::Mark(mark))
 ));
 
+/// `/re/` or `?re?`. The closing delimiter may be left off at the end of
+/// the line.
+fn search_addr(input: &str) -> IResult<&str, LineAddr> {
+    let delim = match input.chars().next() {
+        Some(c) if c == '/' || c == '?' => c,
+        _ => return IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    };
+    match delimited_text(&input[1..], delim, true) {
+        Some((re, rest, closed)) if closed || rest.is_empty() => {
+            IResult::Done(rest, if delim == '/' {
+                LineAddr::ForwardSearch(re)
+            } else {
+                LineAddr::BackwardSearch(re)
+            })
+        },
+        _ => IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    }
+}
+
 named!(line_addr<&str, LineAddr>, alt!(
               period
             | dollar_sign
             | number
             | mark
+            | search_addr
 ));
 
-named!(prefix_plus_minus<&str, Modifier>,
-        alt!(
-              tag!("+") => {|_| Modifier::PrefixPlus }
-            | tag!("-") => {|_| Modifier::PrefixMinus }
-        )
-);
-
-named!(addr_prefix<&str, Addr>, 
-        do_parse!(
-            prefix: opt!(prefix_plus_minus) >>
-            primary: call!(line_addr) >>
-            (Addr::new(primary, prefix))
-));
-
-/*
-named!(suffix_plus_minus<&str, Modifier>,
-        alt!(
-              tag!("+") => {|_| Modifier::SuffixPlus(None) }
-            | tag!("-") => {|_| Modifier::SuffixMinus(None) }
-        )
-);
-
-named!(addr_suffix<&str, Addr>,
-        do_parse!(
-            primary: call!(line_addr) >>
-            suffix: opt!(suffix_plus_minus) >>
-            (Addr::new(primary, suffix))
-));
-*/
+/// Any number of `+n`, `-n`, `+`, `-` or bare `n` offsets, which may be
+/// separated by blanks.
+fn offsets(input: &str) -> IResult<&str, Vec<Modifier>> {
+    let mut modifiers = vec![];
+    let mut rest = input;
+    loop {
+        let next = rest.trim_start_matches(&[' ', '\t'][..]);
+        let (plus, digits) = match next.chars().next() {
+            Some('+') => (true, &next[1..]),
+            Some('-') => (false, &next[1..]),
+            Some(c) if c.is_ascii_digit() => (true, next),
+            _ => break,
+        };
+        let len = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+        let n = if len > 0 {
+            match digits[..len].parse() {
+                Ok(n) => Some(n),
+                Err(_) => return IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+            }
+        } else {
+            None
+        };
+        modifiers.push(if plus {
+            Modifier::SuffixPlus(n)
+        } else {
+            Modifier::SuffixMinus(n)
+        });
+        rest = &digits[len..];
+    }
+    IResult::Done(rest, modifiers)
+}
 
-named!(addr<&str, Addr>, call!(addr_prefix));
+/// An address is a line address followed by any number of offsets. With no
+/// line address, the offsets are from `.`, so `-` is the line before `.`.
+fn addr(input: &str) -> IResult<&str, Addr> {
+    let (rest, primary) = match line_addr(input) {
+        IResult::Done(rest, primary) => (rest, Some(primary)),
+        _ => (input, None),
+    };
+    let (rest, modifiers) = try_parse!(rest, offsets);
+    match primary {
+        Some(primary) => IResult::Done(rest, Addr::new(primary, modifiers)),
+        None if !modifiers.is_empty() => IResult::Done(rest, Addr::new(LineAddr::Period, modifiers)),
+        None => IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    }
+}
 
-named!(range< &str, LineRange >, do_parse!(
-            start: opt!(addr) >>
-            mode: comma_or_semicolon >>
-            end: opt!(addr) >>
-            (LineRange(start, mode, end))
-));
+/// Addresses separated by `,` or `;`. Only the last two are used, and a
+/// single address `addr` is the same as `addr,addr`.
+fn range(input: &str) -> IResult<&str, LineRange> {
+    let mut separated = vec![];
+    let mut rest = input;
+    let last = loop {
+        let (after_addr, addr) = match addr(rest) {
+            IResult::Done(r, a) => (r, Some(a)),
+            _ => (rest, None),
+        };
+        match comma_or_semicolon(after_addr) {
+            IResult::Done(r, mode) => {
+                separated.push((addr, mode));
+                rest = r;
+            },
+            _ => {
+                rest = after_addr;
+                break addr;
+            },
+        }
+    };
+    match separated.pop() {
+        Some((start, mode)) => {
+            let mut range = LineRange::new(start, mode, last);
+            range.leading = separated.into_iter()
+                                     .filter_map(|(a, m)| a.map(|a| (a, m)))
+                                     .collect();
+            IResult::Done(rest, range)
+        },
+        None => match last {
+            Some(addr) => IResult::Done(rest, LineRange::new(Some(addr), Mode::Comma, None)),
+            None => IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+        },
+    }
+}
 
 /* End Line Addressing */
 
@@ -204,6 +383,49 @@ named!(print_numbered_lines< &str, Command >,
             (Command::PrintNumbered(range))
 ));
 
+named!(list_lines< &str, Command >,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("l") >>
+            (Command::List(range))
+));
+
+named!(print_line_number< &str, Command >,
+        do_parse!(
+            addr: opt!(addr) >>
+            tag!("=") >>
+            (Command::PrintLineNumber(addr))
+));
+
+named!(scroll< &str, Command >,
+        do_parse!(
+            addr: opt!(addr) >>
+            tag!("z") >>
+            count: opt!(complete!(num_str)) >>
+            (Command::Scroll(addr, count.map(|c| c as usize)))
+));
+
+named!(yank_to_cut< &str, Command >,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("y") >>
+            (Command::YankToCut(range))
+));
+
+named!(insert_from_cut< &str, Command >,
+        do_parse!(
+            addr: opt!(addr) >>
+            tag!("x") >>
+            (Command::InsertFromCut(addr))
+));
+
+named!(shell_command< &str, Command >,
+        do_parse!(
+            tag!("!") >>
+            command: call!(nom::rest_s) >>
+            (Command::ShellCmd(command.into()))
+));
+
 named!(quit<&str, Command>,
         do_parse!(
             tag!("q") >>
@@ -250,21 +472,29 @@ named!(save_file<&str, Command>,
 
 named!(save_append<&str, Command>,
         do_parse!(
-            range: opt!(range) >>
-            tag!("W") >>
-            call!(nom::multispace) >>
-            filename: opt!(call!(nom::rest_s)) >>
-            (Command::SaveAppend(range, filename.map(|s| s.into())))
-));
+                range: opt!(range) >>
+                filename: alt_complete!(
+                      separated_pair!(
+                          tag!("W"), call!(nom::multispace), call!(nom::rest_s)) => {|r: (_, &str)|
+                              Some(r.1.into())
+                          }
+                    | tag!("W") => {|_| None}
+                ) >>
+                (Command::SaveAppend(range, filename)))
+);
 
 named!(save_and_quit<&str, Command>,
         do_parse!(
-            range: opt!(range) >>
-            tag!("wq") >>
-            call!(nom::multispace) >>
-            filename: opt!(call!(nom::rest_s)) >>
-            (Command::SaveAndQuit(range, filename.map(|s| s.into())))
-));
+                range: opt!(range) >>
+                filename: alt_complete!(
+                      separated_pair!(
+                          tag!("wq"), call!(nom::multispace), call!(nom::rest_s)) => {|r: (_, &str)|
+                              Some(r.1.into())
+                          }
+                    | tag!("wq") => {|_| None}
+                ) >>
+                (Command::SaveAndQuit(range, filename)))
+);
 
 named!(default_filename<&str, Command>,
         alt_complete!(
@@ -297,6 +527,19 @@ named!(uncond_edit_file<&str, Command>,
 );
         
 
+named!(read_file<&str, Command>,
+        do_parse!(
+            addr: opt!(addr) >>
+            filename: alt_complete!(
+                  separated_pair!(
+                      tag!("r"), call!(nom::multispace), call!(nom::rest_s)) => {|r: (_, &str)|
+                          Some(r.1.into())
+                      }
+                | tag!("r") => {|_| None}
+            ) >>
+            (Command::ReadFile(addr, filename)))
+);
+
 named!(mark_line<&str, Command>,
         do_parse!(
             addr: opt!(addr) >>
@@ -305,6 +548,29 @@ named!(mark_line<&str, Command>,
             (Command::MarkLine(addr, mark))
 ));
 
+named!(join_lines<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("j") >>
+            (Command::JoinLines(range))
+));
+
+named!(move_lines<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("m") >>
+            dest: opt!(complete!(addr)) >>
+            (Command::MoveLines(range, dest))
+));
+
+named!(transfer<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("t") >>
+            dest: opt!(complete!(addr)) >>
+            (Command::Transfer(range, dest))
+));
+
 named!(insert_text<&str, Command>,
         do_parse!(
             addr: opt!(addr) >>
@@ -319,6 +585,213 @@ named!(change_text<&str, Command>,
             (Command::ChangeText(range))
 ));
 
+/// Read up to the next unescaped `delim`, returning the text, the remaining
+/// input and whether the closing delimiter was found.
+///
+/// In a regular expression (`is_regex`), the delimiter is literal inside a
+/// bracket expression and a newline may not appear.
+fn delimited_text(input: &str, delim: char, is_regex: bool) -> Option<(String, &str, bool)> {
+    let mut text = String::new();
+    let mut in_brackets = false;
+    let mut chars = input.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, e)) if e == delim => text.push(e),
+                Some((_, e)) => {
+                    text.push('\\');
+                    text.push(e);
+                },
+                None => text.push('\\'),
+            },
+            '\n' if is_regex => return None,
+            '[' if is_regex && !in_brackets => {
+                in_brackets = true;
+                text.push(c);
+                // a leading `]` (or `^]`) doesn't close the expression
+                if let Some(&(_, '^')) = chars.peek() {
+                    text.push('^');
+                    chars.next();
+                }
+                if let Some(&(_, ']')) = chars.peek() {
+                    text.push(']');
+                    chars.next();
+                }
+            },
+            ']' if in_brackets => {
+                in_brackets = false;
+                text.push(c);
+            },
+            _ if c == delim && !in_brackets => {
+                return Some((text, &input[idx + c.len_utf8()..], true));
+            },
+            _ => text.push(c),
+        }
+    }
+    Some((text, &input[input.len()..], false))
+}
+
+/// Parse `/re/`, returning the delimiter along with the regex. The delimiter
+/// may be any character other than whitespace or an alphanumeric, which are
+/// left for the flags of `s`.
+fn regex(input: &str) -> IResult<&str, (char, String)> {
+    let delim = match input.chars().next() {
+        Some(c) if !c.is_whitespace() && !c.is_alphanumeric() => c,
+        _ => return IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    };
+    match delimited_text(&input[delim.len_utf8()..], delim, true) {
+        Some((regex, rest, true)) => IResult::Done(rest, (delim, regex)),
+        _ => IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    }
+}
+
+/// Parse `/re/replacement/`, noting whether the final delimiter was present.
+fn regex_and_replacement(input: &str) -> IResult<&str, (String, String, bool)> {
+    let (rest, (delim, regex)) = try_parse!(input, regex);
+    match delimited_text(rest, delim, false) {
+        Some((replacement, rest, closed)) => IResult::Done(rest, (regex, replacement, closed)),
+        None => IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    }
+}
+
+fn subst_flags(input: &str) -> IResult<&str, SubstFlags> {
+    let mut flags = SubstFlags::default();
+    let mut nth = String::new();
+    let mut end = input.len();
+    for (idx, c) in input.char_indices() {
+        match c {
+            'g' => flags.global = true,
+            '0'..='9' => nth.push(c),
+            _ => {
+                end = idx;
+                break;
+            },
+        }
+    }
+    if !nth.is_empty() {
+        flags.nth = match nth.parse() {
+            Ok(n) if n > 0 => n,
+            _ => return IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+        };
+    }
+    IResult::Done(&input[end..], flags)
+}
+
+named!(substitute<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("s") >>
+            args: call!(regex_and_replacement) >>
+            flags: call!(subst_flags) >>
+            ({
+                let (regex, replacement, closed) = args;
+                let command = Command::Substitute(range, regex, replacement, flags);
+                // leaving off the final delimiter implies `p`
+                if closed {
+                    command
+                } else {
+                    command.with_suffix(PrintFlags::PRINT)
+                }
+            })
+));
+
+/// The flags of `s` when it repeats the last substitution
+fn repeat_flags(input: &str) -> IResult<&str, RepeatFlags> {
+    let mut flags = RepeatFlags::default();
+    let mut nth = String::new();
+    let mut end = input.len();
+    for (idx, c) in input.char_indices() {
+        match c {
+            'g' => flags.toggle_global = !flags.toggle_global,
+            'r' => flags.last_regex = true,
+            '0'..='9' => nth.push(c),
+            _ => {
+                end = idx;
+                break;
+            },
+        }
+    }
+    if !nth.is_empty() {
+        flags.nth = match nth.parse() {
+            Ok(n) if n > 0 => Some(n),
+            _ => return IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+        };
+    }
+    IResult::Done(&input[end..], flags)
+}
+
+named!(repeat_subst<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("s") >>
+            flags: call!(repeat_flags) >>
+            (Command::RepeatSubst(range, flags))
+));
+
+named!(repeat_last_subst<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("&") >>
+            (Command::RepeatSubst(range, RepeatFlags::default()))
+));
+
+named!(global<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("g") >>
+            regex: call!(regex) >>
+            commands: call!(nom::rest_s) >>
+            (Command::Global(range, regex.1, commands.into()))
+));
+
+named!(not_global<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("v") >>
+            regex: call!(regex) >>
+            commands: call!(nom::rest_s) >>
+            (Command::NotGlobal(range, regex.1, commands.into()))
+));
+
+named!(interactive_global<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("G") >>
+            regex: call!(regex) >>
+            (Command::InteractiveGlobal(range, regex.1))
+));
+
+named!(interactive_not_global<&str, Command>,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("V") >>
+            regex: call!(regex) >>
+            (Command::InteractiveNotGlobal(range, regex.1))
+));
+
+named!(undo<&str, Command>,
+        do_parse!(
+            tag!("u") >>
+            (Command::Undo)
+));
+
+/* `U` and `R` walk back and forward through the undo history, which goes
+ * further back than the one change POSIX `u` can undo */
+
+named!(undo_steps<&str, Command>,
+        do_parse!(
+            tag!("U") >>
+            count: opt!(complete!(num_str)) >>
+            (Command::UndoSteps(count.unwrap_or(1) as usize))
+));
+
+named!(redo_steps<&str, Command>,
+        do_parse!(
+            tag!("R") >>
+            count: opt!(complete!(num_str)) >>
+            (Command::RedoSteps(count.unwrap_or(1) as usize))
+));
+
 named!(last_error<&str, Command>,
         do_parse!(
             tag!("h") >>
@@ -331,33 +804,137 @@ named!(toggle_error_expl<&str, Command>,
             (Command::ToggleErrorExpl)
 ));
 
-named!(pub parse_line< &str, Command >,
+/// Any combination of `p`, `l` and `n` after a command
+fn print_suffix(input: &str) -> IResult<&str, PrintFlags> {
+    let mut flags = PrintFlags::default();
+    let mut end = input.len();
+    for (idx, c) in input.char_indices() {
+        match c {
+            'p' => flags.print = true,
+            'l' => flags.list = true,
+            'n' => flags.numbered = true,
+            _ => {
+                end = idx;
+                break;
+            },
+        }
+    }
+    IResult::Done(&input[end..], flags)
+}
+
+named!(comment< &str, Command >,
+        do_parse!(
+            range: opt!(range) >>
+            tag!("#") >>
+            call!(nom::rest_s) >>
+            (Command::Comment(range))
+));
+
+/// An address on its own, or an empty line
+fn null_command(input: &str) -> IResult<&str, Command> {
+    if input.is_empty() {
+        return IResult::Done(input, Command::NullCmd(None));
+    }
+    match range(input) {
+        IResult::Done("", range) => IResult::Done("", Command::NullCmd(Some(range))),
+        _ => IResult::Error(error_position!(nom::ErrorKind::Custom(0), input)),
+    }
+}
+
+/* The commands that can be followed by a print suffix */
+named!(suffixable< &str, Command >,
         alt!(
               print_lines
             | print_numbered_lines
+            | list_lines
+            | print_line_number
+            | scroll
+            | append_text
+            | delete
+            | insert_text
+            | change_text
+            | join_lines
+            | move_lines
+            | transfer
+            | substitute
+            | repeat_subst
+            | repeat_last_subst
+            | undo
+            | yank_to_cut
+            | insert_from_cut
+            | mark_line
+        )
+);
+
+named!(pub parse_line< &str, Command >,
+        alt!(
+              null_command
+            | do_parse!(
+                  command: suffixable >>
+                  flags: call!(print_suffix) >>
+                  (command.with_suffix(flags)))
             | quit
             | hard_quit
             | toggle_show_prompt
-            | append_text
-            | delete
+            | complete!(save_and_quit)
             | save_file
             | save_append
-            | save_and_quit
             | default_filename
             | edit_file
             | uncond_edit_file
-            | mark_line
-            | insert_text
-            | change_text
+            | read_file
+            | global
+            | not_global
+            | interactive_global
+            | interactive_not_global
+            | undo_steps
+            | redo_steps
+            | shell_command
+            | comment
             | last_error
             | toggle_error_expl
         )
 );
 
+/// Parse a whole command line, failing if any of it is left over.
+pub fn parse_command(line: &str) -> Result<Command> {
+    match parse_line(line) {
+        IResult::Done("", command) => Ok(command),
+        x => {
+            debug!("Could not parse {:?}, got {:?}", line, x);
+            Err(ErrorKind::Unknown.into())
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use commands::Command;
+    use Buffer;
+
+    #[test]
+    fn test_print_suffixes() {
+        let flags = |print, list, numbered| PrintFlags { print, list, numbered };
+        assert_finished_and_eq!(parse_line("dp"), Command::PrintSuffix(Box::new(Command::Delete(None)), flags(true, false, false)));
+        assert_finished_and_eq!(parse_line("m0ln"), Command::PrintSuffix(Box::new(Command::MoveLines(None, Some(Addr::number(0)))), flags(false, true, true)));
+        assert_finished_and_eq!(parse_line("up"), Command::PrintSuffix(Box::new(Command::Undo), flags(true, false, false)));
+        // no suffix, no wrapper
+        assert_finished_and_eq!(parse_line("j"), Command::JoinLines(None));
+        assert_finished_and_eq!(parse_line("kap"), Command::PrintSuffix(Box::new(Command::MarkLine(None, 'a')), PrintFlags::PRINT));
+        // commands that don't take a suffix leave it unparsed
+        assert_eq!(parse_line("qp"), IResult::Done("p", Command::Quit));
+    }
+
+    #[test]
+    fn test_null_command_and_comment() {
+        assert_finished_and_eq!(parse_line(""), Command::NullCmd(None));
+        let range = LineRange::new(Some(Addr::number(5)), Mode::Comma, None);
+        assert_finished_and_eq!(parse_line("5"), Command::NullCmd(Some(range.clone())));
+        assert_finished_and_eq!(parse_line("5# go to line five"), Command::Comment(Some(range)));
+        assert_finished_and_eq!(parse_line("#"), Command::Comment(None));
+        assert!(parse_command("5x5").is_err());
+    }
 
     #[test]
     fn test_write_command() {
@@ -367,7 +944,252 @@ mod tests {
         let input = "w /tmp/filename";
         assert_finished_and_eq!(parse_line(input), Command::SaveFile(None, Some("/tmp/filename".to_string())));
 
+        assert_finished_and_eq!(parse_line("wq"), Command::SaveAndQuit(None, None));
+        assert_finished_and_eq!(parse_line("W"), Command::SaveAppend(None, None));
+
         let input = "1,$w /tmp/filename";
-        assert_finished_and_eq!(parse_line(input), Command::SaveFile(Some(LineRange(Some(Addr::number(1)), Mode::Comma, Some(Addr::dollar_sign()))), Some("/tmp/filename".to_string())));
+        assert_finished_and_eq!(parse_line(input), Command::SaveFile(Some(LineRange::new(Some(Addr::number(1)), Mode::Comma, Some(Addr::dollar_sign()))), Some("/tmp/filename".to_string())));
+    }
+
+    #[test]
+    fn test_substitute_command() {
+        let input = "s/foo/bar/";
+        assert_finished_and_eq!(parse_line(input), Command::Substitute(None, "foo".into(), "bar".into(), SubstFlags::default()));
+
+        let input = "'a,'bs|a\\|b|c\\|d|3gp";
+        let flags = SubstFlags { global: true, nth: 3 };
+        let range = LineRange::new(Some(Addr::mark('a')), Mode::Comma, Some(Addr::mark('b')));
+        let print = PrintFlags::PRINT;
+        assert_finished_and_eq!(parse_line(input), Command::PrintSuffix(Box::new(Command::Substitute(Some(range), "a|b".into(), "c|d".into(), flags)), print));
+
+        let input = "s/[/]/x";
+        assert_finished_and_eq!(parse_line(input), Command::PrintSuffix(Box::new(Command::Substitute(None, "[/]".into(), "x".into(), SubstFlags::default())), print));
+
+        // once the replacement is closed, a suffix is given explicitly
+        let input = "s/a/b/n";
+        let numbered = PrintFlags::NUMBERED;
+        assert_finished_and_eq!(parse_line(input), Command::PrintSuffix(Box::new(Command::Substitute(None, "a".into(), "b".into(), SubstFlags::default())), numbered));
+
+        let input = ",s";
+        assert_finished_and_eq!(parse_line(input), Command::RepeatSubst(Some(LineRange::new(None, Mode::Comma, None)), RepeatFlags::default()));
+
+        let input = "sgr3p";
+        let flags = RepeatFlags { toggle_global: true, nth: Some(3), last_regex: true };
+        assert_finished_and_eq!(parse_line(input), Command::PrintSuffix(Box::new(Command::RepeatSubst(None, flags)), PrintFlags::PRINT));
+
+        let input = "&";
+        assert_finished_and_eq!(parse_line(input), Command::RepeatSubst(None, RepeatFlags::default()));
+
+        let input = "s/a/b\\\nc/";
+        assert_finished_and_eq!(parse_line(input), Command::Substitute(None, "a".into(), "b\\\nc".into(), SubstFlags::default()));
+    }
+
+    #[test]
+    fn test_search_addresses() {
+        let input = "/a\\/b/,?c?d";
+        let range = LineRange::new(Some(Addr::new(LineAddr::ForwardSearch("a/b".into()), vec![])),
+                              Mode::Comma,
+                              Some(Addr::new(LineAddr::BackwardSearch("c".into()), vec![])));
+        assert_finished_and_eq!(parse_line(input), Command::Delete(Some(range)));
+
+        let input = "//,/x/p";
+        let range = LineRange::new(Some(Addr::new(LineAddr::ForwardSearch("".into()), vec![])),
+                              Mode::Comma,
+                              Some(Addr::new(LineAddr::ForwardSearch("x".into()), vec![])));
+        assert_finished_and_eq!(parse_line(input), Command::Print(Some(range)));
+    }
+
+    #[test]
+    fn test_range_defaults() {
+        let buffer = Buffer::from(vec!["a".to_string(), "b".into(), "c".into(), "d".into()]);
+        let mut config = Config {
+            current_index: Some(1),
+            ..Default::default()
+        };
+        let mut numbers = |input: &str| {
+            match range(input) {
+                IResult::Done("", r) => r.line_numbers(&buffer, &mut config).unwrap(),
+                x => panic!("could not parse {:?}: {:?}", input, x),
+            }
+        };
+        assert_eq!(numbers(","), (1, 4));
+        assert_eq!(numbers(";"), (2, 4));
+        assert_eq!(numbers(",3"), (1, 3));
+        assert_eq!(numbers("3,"), (3, 3));
+        assert_eq!(numbers("3"), (3, 3));
+        assert_eq!(numbers("1,2,3"), (2, 3));
+    }
+
+    #[test]
+    fn test_semicolon_sets_current_line() {
+        let buffer = Buffer::from(vec!["begin".to_string(), "x".into(), "end".into(),
+                                       "begin".into(), "y".into(), "end".into()]);
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let range = LineRange::new(Some(Addr::new(LineAddr::ForwardSearch("begin".into()), vec![])),
+                                   Mode::Semicolon,
+                                   Some(Addr::new(LineAddr::ForwardSearch("end".into()), vec![])));
+        assert_eq!(range.clone().line_numbers(&buffer, &mut config).unwrap(), (4, 6));
+        assert_eq!(config.current_index, Some(3));
+
+        let mut config = Config {
+            current_index: Some(0),
+            ..Default::default()
+        };
+        let mut comma = range;
+        comma.mode = Mode::Comma;
+        assert_eq!(comma.line_numbers(&buffer, &mut config).unwrap(), (4, 3));
+    }
+
+    #[test]
+    fn test_resolve_needs_real_lines() {
+        let buffer = Buffer::from(vec!["a".to_string(), "b".into()]);
+        let mut config = Config::default();
+        assert_eq!(Addr::number(0).line_number(&buffer, &mut config).unwrap(), 0);
+        assert!(Addr::number(0).resolve(&buffer, &mut config).is_err());
+        assert_eq!(Addr::number(2).resolve(&buffer, &mut config).unwrap(), 1);
+        assert!(Addr::number(3).line_number(&buffer, &mut config).is_err());
+        assert!(Addr::number(u64::MAX).line_number(&buffer, &mut config).is_err());
+        let huge = Some(i64::MAX as u64);
+        let addr = Addr::new(LineAddr::Number(1), vec![Modifier::SuffixPlus(huge), Modifier::SuffixPlus(huge)]);
+        assert!(addr.line_number(&buffer, &mut config).is_err());
+
+        let range = LineRange::new(Some(Addr::number(2)), Mode::Comma, Some(Addr::number(1)));
+        assert!(range.resolve(&buffer, &mut config).is_err());
+        let range = LineRange::new(Some(Addr::number(0)), Mode::Comma, Some(Addr::number(1)));
+        assert!(range.resolve(&buffer, &mut config).is_err());
+        assert_eq!(LineRange::everything().resolve(&buffer, &mut config).unwrap(), (0, 1));
+        assert!(LineRange::everything().resolve(&Buffer::new(), &mut config).is_err());
+    }
+
+    #[test]
+    fn test_address_offsets() {
+        use self::Modifier::*;
+
+        let input = ".++-,$-3p";
+        let range = LineRange::new(Some(Addr::new(LineAddr::Period, vec![SuffixPlus(None), SuffixPlus(None), SuffixMinus(None)])),
+                              Mode::Comma,
+                              Some(Addr::new(LineAddr::DollarSign, vec![SuffixMinus(Some(3))])));
+        assert_finished_and_eq!(parse_line(input), Command::Print(Some(range)));
+
+        let input = "-,/re/+1 2p";
+        let range = LineRange::new(Some(Addr::new(LineAddr::Period, vec![SuffixMinus(None)])),
+                              Mode::Comma,
+                              Some(Addr::new(LineAddr::ForwardSearch("re".into()), vec![SuffixPlus(Some(1)), SuffixPlus(Some(2))])));
+        assert_finished_and_eq!(parse_line(input), Command::Print(Some(range)));
+    }
+
+    #[test]
+    fn test_address_arithmetic() {
+        use self::Modifier::*;
+
+        let buffer = Buffer::from(vec!["a".to_string(), "b".into(), "c".into(), "d".into()]);
+        let mut config = Config {
+            current_index: Some(1),
+            ..Default::default()
+        };
+        config.marks.insert('a', buffer.id(3));
+        let addr = Addr::new(LineAddr::Period, vec![SuffixPlus(None), SuffixPlus(None), SuffixMinus(None)]);
+        assert_eq!(addr.line_number(&buffer, &mut config).unwrap(), 3);
+        let addr = Addr::new(LineAddr::Mark('a'), vec![SuffixMinus(Some(2))]);
+        assert_eq!(addr.line_number(&buffer, &mut config).unwrap(), 2);
+        let addr = Addr::new(LineAddr::DollarSign, vec![SuffixPlus(Some(1))]);
+        assert!(addr.line_number(&buffer, &mut config).is_err());
+        let addr = Addr::new(LineAddr::Period, vec![SuffixMinus(Some(3))]);
+        assert!(addr.line_number(&buffer, &mut config).is_err());
+    }
+
+    #[test]
+    fn test_search_wraps_around() {
+        let buffer = Buffer::from(vec!["foo".to_string(), "bar".into(), "foo".into(), "baz".into()]);
+        let mut config = Config {
+            current_index: Some(2),
+            ..Default::default()
+        };
+        let forward = Addr::new(LineAddr::ForwardSearch("foo".into()), vec![]);
+        assert_eq!(forward.resolve(&buffer, &mut config).unwrap(), 0);
+        let backward = Addr::new(LineAddr::BackwardSearch("".into()), vec![]);
+        assert_eq!(backward.clone().resolve(&buffer, &mut config).unwrap(), 0);
+        config.current_index = Some(0);
+        assert_eq!(backward.resolve(&buffer, &mut config).unwrap(), 2);
+        let missing = Addr::new(LineAddr::ForwardSearch("qux".into()), vec![]);
+        assert!(missing.resolve(&buffer, &mut config).is_err());
+    }
+
+    #[test]
+    fn test_undo_commands() {
+        assert_finished_and_eq!(parse_line("u"), Command::Undo);
+        assert_finished_and_eq!(parse_line("U"), Command::UndoSteps(1));
+        assert_finished_and_eq!(parse_line("U12"), Command::UndoSteps(12));
+        assert_finished_and_eq!(parse_line("R3"), Command::RedoSteps(3));
+    }
+
+    #[test]
+    fn test_join_command() {
+        assert_finished_and_eq!(parse_line("j"), Command::JoinLines(None));
+    }
+
+    #[test]
+    fn test_move_and_transfer_commands() {
+        let range = LineRange::new(Some(Addr::number(1)), Mode::Comma, Some(Addr::number(2)));
+        assert_finished_and_eq!(parse_line("1,2m$"), Command::MoveLines(Some(range), Some(Addr::dollar_sign())));
+        assert_finished_and_eq!(parse_line("t0"), Command::Transfer(None, Some(Addr::number(0))));
+        assert_finished_and_eq!(parse_line("t"), Command::Transfer(None, None));
+    }
+
+    #[test]
+    fn test_read_command() {
+        assert_finished_and_eq!(parse_line("0r foo"), Command::ReadFile(Some(Addr::number(0)), Some("foo".into())));
+        assert_finished_and_eq!(parse_line("r !ls -l"), Command::ReadFile(None, Some("!ls -l".into())));
+        assert_finished_and_eq!(parse_line("r"), Command::ReadFile(None, None));
+    }
+
+    #[test]
+    fn test_list_command() {
+        assert_finished_and_eq!(parse_line(",l"), Command::List(Some(LineRange::new(None, Mode::Comma, None))));
+    }
+
+    #[test]
+    fn test_line_number_command() {
+        assert_finished_and_eq!(parse_line("$="), Command::PrintLineNumber(Some(Addr::dollar_sign())));
+        assert_finished_and_eq!(parse_line("="), Command::PrintLineNumber(None));
+    }
+
+    #[test]
+    fn test_scroll_command() {
+        assert_finished_and_eq!(parse_line("z"), Command::Scroll(None, None));
+        assert_finished_and_eq!(parse_line("1z5n"), Command::PrintSuffix(Box::new(Command::Scroll(Some(Addr::number(1)), Some(5))), PrintFlags::NUMBERED));
+    }
+
+    #[test]
+    fn test_cut_buffer_commands() {
+        assert_finished_and_eq!(parse_line(",y"), Command::YankToCut(Some(LineRange::new(None, Mode::Comma, None))));
+        assert_finished_and_eq!(parse_line("0x"), Command::InsertFromCut(Some(Addr::number(0))));
+    }
+
+    #[test]
+    fn test_shell_command() {
+        assert_finished_and_eq!(parse_line("!ls %"), Command::ShellCmd("ls %".into()));
+        assert_finished_and_eq!(parse_line("!!"), Command::ShellCmd("!".into()));
+    }
+
+    #[test]
+    fn test_global_command() {
+        let input = "g/re/";
+        assert_finished_and_eq!(parse_line(input), Command::Global(None, "re".into(), "".into()));
+
+        let input = "1,$g|a/b|s/x/y/\\\np";
+        assert_finished_and_eq!(parse_line(input), Command::Global(Some(LineRange::everything()), "a/b".into(), "s/x/y/\\\np".into()));
+
+        let input = "v/keep/d";
+        assert_finished_and_eq!(parse_line(input), Command::NotGlobal(None, "keep".into(), "d".into()));
+
+        let input = "G/re/";
+        assert_finished_and_eq!(parse_line(input), Command::InteractiveGlobal(None, "re".into()));
+
+        let input = "V,re,";
+        assert_finished_and_eq!(parse_line(input), Command::InteractiveNotGlobal(None, "re".into()));
     }
 }
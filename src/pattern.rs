@@ -0,0 +1,161 @@
+use regex::{self, Regex};
+
+use errors::*;
+use Config;
+
+/// Compile a POSIX basic regular expression, as used by ed, into a `Regex`.
+pub fn compile(pattern: &str) -> Result<Regex> {
+    let translated = translate(pattern)?;
+    debug!("translated pattern {:?} to {:?}", pattern, translated);
+    Regex::new(&translated).map_err(|e| e.to_string().into())
+}
+
+/// Compile `pattern`, or the last pattern used if `pattern` is empty.
+///
+/// A non-empty pattern becomes the new "last pattern".
+pub fn compile_or_last(pattern: &str, cfg: &mut Config) -> Result<Regex> {
+    if pattern.is_empty() {
+        match cfg.last_regex {
+            Some(ref last) => compile(last),
+            None => Err("No previous pattern".into()),
+        }
+    } else {
+        let re = compile(pattern)?;
+        cfg.last_regex = Some(pattern.into());
+        Ok(re)
+    }
+}
+
+/// Translate a POSIX BRE (plus the usual GNU `\+`, `\?`, `\|`, `\<` and `\>`
+/// extensions) into the syntax understood by the `regex` crate.
+fn translate(pattern: &str) -> Result<String> {
+    let chars = pattern.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(pattern.len());
+    // `*` is literal at the start of an expression or a group
+    let mut at_start = true;
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        let next_at_start = match c {
+            '\\' => {
+                idx += 1;
+                let escaped = match chars.get(idx) {
+                    Some(e) => *e,
+                    None => return Err("Trailing backslash (\\)".into()),
+                };
+                match escaped {
+                    '(' | ')' | '{' | '}' | '+' | '?' | '|' => out.push(escaped),
+                    '<' | '>' => out.push_str(r"\b"),
+                    '1'..='9' => return Err("Back-references in patterns are not supported".into()),
+                    'w' | 'W' | 's' | 'S' | 'b' | 'B' | 'n' | 't' => {
+                        out.push('\\');
+                        out.push(escaped);
+                    },
+                    _ => out.push_str(&regex::escape(&escaped.to_string())),
+                }
+                escaped == '(' || escaped == '|'
+            },
+            '[' => {
+                idx = bracket_expression(&chars, idx, &mut out)?;
+                false
+            },
+            '*' if at_start => {
+                out.push_str(r"\*");
+                false
+            },
+            '^' if idx == 0 => {
+                out.push('^');
+                true
+            },
+            '$' if idx == chars.len() - 1 => {
+                out.push('$');
+                false
+            },
+            '.' | '*' => {
+                out.push(c);
+                false
+            },
+            _ => {
+                out.push_str(&regex::escape(&c.to_string()));
+                false
+            },
+        };
+        at_start = next_at_start;
+        idx += 1;
+    }
+    Ok(out)
+}
+
+/// Copy the bracket expression starting at `chars[start]` into `out`,
+/// returning the index of its closing `]`.
+fn bracket_expression(chars: &[char], start: usize, out: &mut String) -> Result<usize> {
+    let mut idx = start + 1;
+    out.push('[');
+    if chars.get(idx) == Some(&'^') {
+        out.push('^');
+        idx += 1;
+    }
+    // a `]` straight after the opening bracket is a literal
+    if chars.get(idx) == Some(&']') {
+        out.push_str(r"\]");
+        idx += 1;
+    }
+    while idx < chars.len() {
+        let c = chars[idx];
+        match c {
+            ']' => {
+                out.push(']');
+                return Ok(idx);
+            },
+            '[' if chars.get(idx + 1) == Some(&':') => {
+                let close = (idx + 2..chars.len().saturating_sub(1)).find(|&i| {
+                    chars[i] == ':' && chars[i + 1] == ']'
+                });
+                match close {
+                    Some(close) => {
+                        out.extend(&chars[idx..close + 2]);
+                        idx = close + 1;
+                    },
+                    None => return Err("Unbalanced brackets ([])".into()),
+                }
+            },
+            '\\' | '[' | '&' | '~' => {
+                out.push('\\');
+                out.push(c);
+            },
+            '-' if chars.get(idx + 1) == Some(&'-') => {
+                out.push_str(r"\-");
+            },
+            _ => out.push(c),
+        }
+        idx += 1;
+    }
+    Err("Unbalanced brackets ([])".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, translate};
+
+    #[test]
+    fn groups_and_intervals_are_escaped_in_bre() {
+        assert_eq!(translate(r"\(ab\)\{2\}").unwrap(), "(ab){2}");
+        assert_eq!(translate("(ab){2}").unwrap(), r"\(ab\)\{2\}");
+    }
+
+    #[test]
+    fn leading_star_and_anchors() {
+        assert_eq!(translate("*a").unwrap(), r"\*a");
+        assert_eq!(translate(r"\(*a\)").unwrap(), r"(\*a)");
+        assert_eq!(translate("^a^$b$").unwrap(), r"^a\^\$b$");
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert_eq!(translate("[]a]").unwrap(), r"[\]a]");
+        assert_eq!(translate(r"[\.]").unwrap(), r"[\\.]");
+        assert_eq!(translate("[[:alpha:]-]").unwrap(), "[[:alpha:]-]");
+        assert!(translate("[abc").is_err());
+        assert!(compile("[[:digit:]]x").unwrap().is_match("1x"));
+    }
+}